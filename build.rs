@@ -7,6 +7,8 @@ fn main() -> anyhow::Result<()> {
     bd(build_data::set_SOURCE_TIMESTAMP())?;
     bd(build_data::set_RUSTC_VERSION())?;
     bd(build_data::no_debug_rebuilds())?;
+    // embedded by sqlx::migrate!()
+    println!("cargo:rerun-if-changed=migrations");
 
     Ok(())
 }
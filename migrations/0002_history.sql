@@ -0,0 +1,16 @@
+-- 0002_history.sql
+-- Channel messages for !grep, channel names are stored lowercased
+
+create table if not exists history (
+    id bigserial primary key,
+    seen bigint not null,
+    channel text not null,
+    nick text not null,
+    msg text not null,
+    msg_tsv tsvector generated always as (to_tsvector('simple', msg)) stored
+);
+
+create index if not exists history_msg_tsv_idx on history using gin (msg_tsv);
+create index if not exists history_channel_seen_idx on history (channel, seen);
+
+-- EOF
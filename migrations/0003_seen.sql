@@ -0,0 +1,18 @@
+-- 0003_seen.sql
+-- The last thing each nick did on each channel for !seen, one row per nick and channel.
+-- nick_key and channel are lowercased, nick keeps the case it was last seen with.
+
+create table if not exists seen (
+    nick_key text not null,
+    channel text not null,
+    nick text not null,
+    seen bigint not null,
+    action text not null,
+    -- the other nick of a nick change or kick
+    other text not null default '',
+    -- message text or part/quit/kick reason
+    msg text not null default '',
+    primary key (nick_key, channel)
+);
+
+-- EOF
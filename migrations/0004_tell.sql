@@ -0,0 +1,19 @@
+-- 0004_tell.sql
+-- Pending !tell messages, removed when delivered. The *_key columns are lowercased nicks.
+
+create table if not exists tell (
+    id bigserial primary key,
+    created bigint not null,
+    -- where the message was left, empty if in private
+    channel text not null,
+    sender text not null,
+    sender_key text not null,
+    recipient text not null,
+    recipient_key text not null,
+    msg text not null
+);
+
+create index if not exists tell_recipient_key_idx on tell (recipient_key);
+create index if not exists tell_sender_key_idx on tell (sender_key);
+
+-- EOF
@@ -0,0 +1,18 @@
+-- 0005_reminder.sql
+-- Pending !remind reminders, removed when delivered. Times are unix seconds in UTC.
+
+create table if not exists reminder (
+    id bigserial primary key,
+    created bigint not null,
+    due bigint not null,
+    -- where to deliver, empty for a private message
+    channel text not null,
+    nick text not null,
+    nick_key text not null,
+    msg text not null
+);
+
+create index if not exists reminder_due_idx on reminder (due);
+create index if not exists reminder_nick_key_idx on reminder (nick_key);
+
+-- EOF
@@ -0,0 +1,16 @@
+-- 0006_karma.sql
+-- Karma scores per channel. channel and thing_key are lowercased, thing keeps the case it
+-- was last given with.
+
+create table if not exists karma (
+    channel text not null,
+    thing_key text not null,
+    thing text not null,
+    score bigint not null,
+    updated bigint not null,
+    primary key (channel, thing_key)
+);
+
+create index if not exists karma_channel_score_idx on karma (channel, score);
+
+-- EOF
@@ -0,0 +1,15 @@
+-- 0007_quote.sql
+-- The channel quote database, channel names are stored lowercased
+
+create table if not exists quote (
+    id bigserial primary key,
+    channel text not null,
+    added bigint not null,
+    -- who added it
+    nick text not null,
+    quote text not null
+);
+
+create index if not exists quote_channel_idx on quote (channel);
+
+-- EOF
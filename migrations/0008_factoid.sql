@@ -0,0 +1,29 @@
+-- 0008_factoid.sql
+-- Factoids for !learn and ??. The channel is lowercased, empty for the global factoids,
+-- and the key is lowercased. Every change is also written to factoid_history.
+
+create table if not exists factoid (
+    channel text not null,
+    key text not null,
+    value text not null,
+    nick text not null,
+    updated bigint not null,
+    locked boolean not null default false,
+    primary key (channel, key)
+);
+
+create table if not exists factoid_history (
+    id bigserial primary key,
+    channel text not null,
+    key text not null,
+    -- learn, forget, lock or unlock
+    action text not null,
+    -- the new value of a learn
+    value text not null default '',
+    nick text not null,
+    ts bigint not null
+);
+
+create index if not exists factoid_history_channel_key_idx on factoid_history (channel, key);
+
+-- EOF
@@ -0,0 +1,145 @@
+// admin.rs
+
+// The privileged commands, shared by the private message handlers and the control socket.
+// Each command returns the lines to reply with.
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminCmd {
+    DumpAcl,
+    Join,
+    Part,
+    Nick,
+    Reload,
+    Say,
+    Status,
+    Reconnect,
+}
+
+impl AdminCmd {
+    pub const ALL: &[AdminCmd] = &[
+        AdminCmd::DumpAcl,
+        AdminCmd::Join,
+        AdminCmd::Part,
+        AdminCmd::Nick,
+        AdminCmd::Reload,
+        AdminCmd::Say,
+        AdminCmd::Status,
+        AdminCmd::Reconnect,
+    ];
+
+    // The fixed names used on the control socket, the PM names come from the config
+    pub fn name(self) -> &'static str {
+        match self {
+            AdminCmd::DumpAcl => "dumpacl",
+            AdminCmd::Join => "join",
+            AdminCmd::Part => "part",
+            AdminCmd::Nick => "nick",
+            AdminCmd::Reload => "reload",
+            AdminCmd::Say => "say",
+            AdminCmd::Status => "status",
+            AdminCmd::Reconnect => "reconnect",
+        }
+    }
+
+    pub fn usage(self) -> &'static str {
+        match self {
+            AdminCmd::Join => "join <channel>",
+            AdminCmd::Part => "part <channel>",
+            AdminCmd::Nick => "nick <nick>",
+            AdminCmd::Say => "say [#channel] <message>",
+            c => c.name(),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        AdminCmd::ALL.iter().copied().find(|c| c.name() == name)
+    }
+}
+
+pub async fn admin_command(bot: &Arc<IrcBot>, cmd: AdminCmd, args: &str) -> anyhow::Result<Vec<String>> {
+    let args = args.trim();
+    match cmd {
+        AdminCmd::DumpAcl => admin_dump_acl(bot).await,
+        AdminCmd::Join => {
+            let channel = first_arg(cmd, args)?;
+            info!("Trying to join channel {channel}");
+            bot.clone().new_op(IrcOp::Join(channel)).await?;
+            Ok(Vec::new())
+        }
+        AdminCmd::Part => {
+            let channel = first_arg(cmd, args)?;
+            info!("Trying to part channel {channel}");
+            bot.clone().new_op(IrcOp::Part(channel)).await?;
+            Ok(Vec::new())
+        }
+        AdminCmd::Nick => {
+            let nick = first_arg(cmd, args)?;
+            info!("Trying to change nick to {nick}");
+            bot.clone().new_op(IrcOp::Nick(nick)).await?;
+            Ok(Vec::new())
+        }
+        AdminCmd::Reload => {
+            // *** Try reloading all runtime configs ***
+            error!("*** RELOADING CONFIG ***");
+            match bot.clone().reload().await {
+                Ok(_) => Ok(vec!["*** Reload successful.".to_string()]),
+                Err(e) => bail!("*** Reload failed: {e}"),
+            }
+        }
+        AdminCmd::Say => {
+            if args.starts_with('#')
+                // channel was specified
+                && let Some((channel, msg)) = args.split_once(' ')
+            {
+                bot.clone().new_msg(channel, msg).await?;
+                return Ok(Vec::new());
+            }
+
+            // use the configured (default) channel name
+            let cfg_channel = bot.config.read().await.channel.clone();
+            bot.clone().new_msg(&cfg_channel, args).await?;
+            Ok(Vec::new())
+        }
+        AdminCmd::Status => Ok(bot.status().await),
+        AdminCmd::Reconnect => {
+            info!("Reconnect requested");
+            bot.request_reconnect()?;
+            Ok(vec!["Reconnecting".to_string()])
+        }
+    }
+}
+
+fn first_arg(cmd: AdminCmd, args: &str) -> anyhow::Result<String> {
+    match args.split_whitespace().next() {
+        Some(arg) => Ok(arg.to_string()),
+        None => bail!("Usage: {}", cmd.usage()),
+    }
+}
+
+async fn admin_dump_acl(bot: &Arc<IrcBot>) -> anyhow::Result<Vec<String>> {
+    info!("Dumping ACLs");
+    let config = bot.config.read().await;
+    let mode_o_acl = &config
+        .mode_o_acl_rt
+        .as_ref()
+        .ok_or_else(|| anyhow!("no mode_o_acl_rt"))?
+        .acl_str;
+    let auto_o_acl = &config
+        .auto_o_acl_rt
+        .as_ref()
+        .ok_or_else(|| anyhow!("no auto_o_acl_rt"))?
+        .acl_str;
+
+    let mut lines = Vec::with_capacity(mode_o_acl.len() + auto_o_acl.len() + 4);
+    lines.push("My +o ACL:".to_string());
+    lines.extend(mode_o_acl.iter().cloned());
+    lines.push("<EOF>".to_string());
+    lines.push("My auto +o ACL:".to_string());
+    lines.extend(auto_o_acl.iter().cloned());
+    lines.push("<EOF>".to_string());
+    Ok(lines)
+}
+
+// EOF
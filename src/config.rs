@@ -1,6 +1,6 @@
 // config.rs
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use crate::*;
 
@@ -17,6 +17,20 @@ pub struct OptsCommon {
     pub bot_config: String,
     #[arg(short, long, default_value = "$HOME/sjmb/config/irc.toml")]
     pub irc_config: String,
+
+    #[command(subcommand)]
+    pub command: Option<OptsCommand>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum OptsCommand {
+    /// Send a command to the running bot over its control socket, e.g. `ctl say #chan hello`
+    Ctl {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Apply the pending database migrations from migrations/ to `url_log_db` and exit
+    Migrate,
 }
 
 impl OptsCommon {
@@ -0,0 +1,187 @@
+// control.rs
+
+// Local administration over a Unix socket, used by `sjmb ctl`. A client sends one command
+// line and reads the reply lines, the last one being "OK" or "ERR <reason>". Whoever can
+// open the socket is trusted like a privileged nick, so it is only accessible to our user.
+
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::*;
+
+const CONTROL_LINE_MAX: u64 = 4096;
+// in milliseconds
+const CONTROL_REQUEST_TIMEOUT: u64 = 5000;
+const CONTROL_ACCEPT_BACKOFF: u64 = 100;
+
+const CONTROL_OK: &str = "OK";
+const CONTROL_ERR: &str = "ERR ";
+
+pub async fn start_control_socket(bot: Arc<IrcBot>) -> anyhow::Result<()> {
+    let path = bot.config.read().await.control_socket.clone();
+    if path.is_empty() {
+        return Ok(());
+    }
+    // a socket left over from an earlier run would make bind fail
+    match fs::symlink_metadata(&path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(&path)?,
+        Ok(_) => bail!("Control socket path {path} exists and is not a socket"),
+        Err(_) => {}
+    }
+    let listener = bind_private(Path::new(&path))?;
+    info!("Control socket listening on {path}");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_control(bot.clone(), stream));
+                }
+                Err(e) => {
+                    error!("Control socket: {e}");
+                    // e.g. out of file descriptors, do not spin
+                    sleep(Duration::from_millis(CONTROL_ACCEPT_BACKOFF)).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Binds in a directory only we can enter and moves the socket into place once it is 0600,
+// so nobody else can connect in between
+fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let tmp_dir = parent.join(format!(".sjmb-ctl-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&tmp_dir);
+    fs::DirBuilder::new().mode(0o700).create(&tmp_dir)?;
+    let tmp_path = tmp_dir.join("s");
+    let res = UnixListener::bind(&tmp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&tmp_path, path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_dir_all(&tmp_dir);
+    res
+}
+
+async fn serve_control(bot: Arc<IrcBot>, stream: UnixStream) {
+    let (rx, mut tx) = stream.into_split();
+    let mut line = String::new();
+    let read = tokio::time::timeout(
+        Duration::from_millis(CONTROL_REQUEST_TIMEOUT),
+        io::BufReader::new(rx).take(CONTROL_LINE_MAX).read_line(&mut line),
+    )
+    .await;
+    if !matches!(read, Ok(Ok(n)) if n > 0) {
+        debug!("Control request not read: {read:?}");
+        return;
+    }
+
+    let reply = match parse_control_request(&line) {
+        Ok((cmd, args)) => {
+            info!("Control command: {}", line.trim_end());
+            metrics().inc("sjmb_commands_total", &[("command", cmd.name())]);
+            admin_command(&bot, cmd, args).await
+        }
+        Err(e) => Err(e),
+    };
+    let mut out = String::new();
+    match reply {
+        Ok(lines) => {
+            for l in lines {
+                out.push_str(&l);
+                out.push('\n');
+            }
+            out.push_str(CONTROL_OK);
+        }
+        Err(e) => {
+            out.push_str(CONTROL_ERR);
+            out.push_str(&e.to_string().ws_collapse());
+        }
+    }
+    out.push('\n');
+    if let Err(e) = tx.write_all(out.as_bytes()).await {
+        debug!("Control response: {e}");
+    }
+}
+
+fn parse_control_request(line: &str) -> anyhow::Result<(AdminCmd, &str)> {
+    let line = line.trim();
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match AdminCmd::from_name(name) {
+        Some(cmd) => Ok((cmd, args.trim())),
+        None => {
+            let usage = AdminCmd::ALL.iter().map(|c| c.usage()).collect::<Vec<_>>();
+            bail!("Unknown command {name:?}, try one of: {}", usage.join(", "))
+        }
+    }
+}
+
+// Sends one command to a running bot and returns its reply lines
+pub async fn control_request(path: &str, request: &str) -> anyhow::Result<Vec<String>> {
+    if path.is_empty() {
+        bail!("No control_socket in the bot config");
+    }
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("Could not connect to {path}: {e}"))?;
+    stream
+        .write_all(format!("{}\n", request.ws_collapse()).as_bytes())
+        .await?;
+
+    let mut lines = io::BufReader::new(stream).lines();
+    let mut reply = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        if line == CONTROL_OK {
+            return Ok(reply);
+        }
+        if let Some(e) = line.strip_prefix(CONTROL_ERR) {
+            bail!("{e}");
+        }
+        reply.push(line);
+    }
+    bail!("Connection closed before the reply was complete")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_requests_are_parsed() {
+        assert_eq!(
+            parse_control_request("say #chan hello there\n").unwrap(),
+            (AdminCmd::Say, "#chan hello there")
+        );
+        assert_eq!(parse_control_request("status").unwrap(), (AdminCmd::Status, ""));
+        assert!(parse_control_request("quit").is_err());
+        assert!(parse_control_request("").is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_is_private_once_in_place() {
+        let dir = env::temp_dir().join(format!("sjmb-control-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sjmb.sock");
+
+        let _listener = bind_private(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+// EOF
@@ -1,5 +1,7 @@
 // db_util.rs
 
+use std::collections::HashSet;
+
 use anyhow::Context;
 use futures::TryStreamExt;
 use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
@@ -52,6 +54,13 @@ where
     Ok(db)
 }
 
+// Applies migrations/*.sql, the ones already applied are skipped. Only run on request with
+// `sjmb migrate`, never by the bot itself.
+pub async fn db_migrate(db: &DbCtx) -> anyhow::Result<()> {
+    sqlx::migrate!().run(&db.dbc).await?;
+    Ok(())
+}
+
 const SQL_INSERT_URL: &str = "insert into url (seen, channel, nick, url) \
     values ($1, $2, $3, $4)";
 
@@ -64,6 +73,7 @@ pub async fn db_add_url(db: &DbCtx, ur: &UrlCtx) -> anyhow::Result<u64> {
                 return Ok(rowcnt);
             }
             Err(e) if attempt == RETRY_CNT => {
+                metrics().inc("sjmb_db_insert_failures_total", &[]);
                 return Err(e)
                     .with_context(|| format!("URL insert failed after {RETRY_CNT} attempts for channel {}", ur.chan));
             }
@@ -72,6 +82,7 @@ pub async fn db_add_url(db: &DbCtx, ur: &UrlCtx) -> anyhow::Result<u64> {
                     "URL insert attempt {attempt}/{RETRY_CNT} failed for channel {}: {e:#}",
                     ur.chan
                 );
+                metrics().inc("sjmb_db_insert_retries_total", &[]);
                 sleep(Duration::new(RETRY_SLEEP, 0)).await;
             }
         }
@@ -115,4 +126,582 @@ pub async fn db_check_url(db: &DbCtx, url: &str, chan: &str, expire_s: i64) -> a
     info!("db_check_url: {res:?}");
     Ok(res)
 }
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbUrlTop {
+    pub url: String,
+    pub cnt: i64,
+    pub first: i64,
+    // who posted it first
+    pub nick: String,
+}
+
+const SQL_URL_LAST: &str = "select id, seen, channel, nick, url from url \
+    where lower(channel) = lower($1) \
+    order by seen desc \
+    limit $2";
+
+const SQL_URL_SEARCH: &str = "select id, seen, channel, nick, url from url \
+    where lower(channel) = lower($1) and strpos(lower(url), lower($2)) > 0 \
+    order by seen desc \
+    limit $3";
+
+const SQL_URL_BY: &str = "select id, seen, channel, nick, url from url \
+    where lower(channel) = lower($1) and lower(nick) = lower($2) \
+    order by seen desc \
+    limit $3";
+
+const SQL_URL_TOP: &str = "select url, count(id) as cnt, min(seen) as first, \
+    (array_agg(nick order by seen))[1] as nick \
+    from url \
+    where lower(channel) = lower($1) \
+    group by url \
+    order by cnt desc, first desc \
+    limit $2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlQuery<'a> {
+    Last,
+    // part of the URL, case insensitive
+    Search(&'a str),
+    By(&'a str),
+}
+
+// Newest first
+pub async fn db_list_urls(db: &DbCtx, chan: &str, query: UrlQuery<'_>, limit: i64) -> anyhow::Result<Vec<DbUrl>> {
+    debug!("db_list_urls(): {chan} {query:?}");
+    let q = match query {
+        UrlQuery::Last => sqlx::query_as::<_, DbUrl>(SQL_URL_LAST).bind(chan),
+        UrlQuery::Search(term) => sqlx::query_as::<_, DbUrl>(SQL_URL_SEARCH).bind(chan).bind(term),
+        UrlQuery::By(nick) => sqlx::query_as::<_, DbUrl>(SQL_URL_BY).bind(chan).bind(nick),
+    };
+    let res = q.bind(limit).fetch_all(&db.dbc).await?;
+    Ok(res)
+}
+
+// The most posted URLs
+pub async fn db_top_urls(db: &DbCtx, chan: &str, limit: i64) -> anyhow::Result<Vec<DbUrlTop>> {
+    let res = sqlx::query_as::<_, DbUrlTop>(SQL_URL_TOP)
+        .bind(chan)
+        .bind(limit)
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbHistory {
+    pub id: i64,
+    pub seen: i64,
+    pub channel: String,
+    pub nick: String,
+    pub msg: String,
+}
+
+const SQL_INSERT_HISTORY: &str = "insert into history (seen, channel, nick, msg) \
+    values ($1, $2, $3, $4)";
+
+pub async fn db_add_history(db: &DbCtx, ts: i64, chan: &str, nick: &str, msg: &str) -> anyhow::Result<u64> {
+    let res = sqlx::query(SQL_INSERT_HISTORY)
+        .bind(ts)
+        .bind(chan.to_lowercase())
+        .bind(nick)
+        .bind(msg)
+        .execute(&db.dbc)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+const SQL_GREP_HISTORY: &str = "select id, seen, channel, nick, msg \
+    from history \
+    where channel = any($1) and msg_tsv @@ websearch_to_tsquery('simple', $2) \
+    order by seen desc \
+    limit $3";
+
+// Most recent first
+pub async fn db_grep_history(db: &DbCtx, chans: &[String], terms: &str, limit: i64) -> anyhow::Result<Vec<DbHistory>> {
+    debug!("db_grep_history(): {chans:?} {terms:?}");
+    let chans = chans.iter().map(|c| c.to_lowercase()).collect::<Vec<_>>();
+    let res = sqlx::query_as::<_, DbHistory>(SQL_GREP_HISTORY)
+        .bind(chans)
+        .bind(terms)
+        .bind(limit)
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbSeen {
+    pub channel: String,
+    pub nick: String,
+    pub seen: i64,
+    pub action: String,
+    pub other: String,
+    pub msg: String,
+}
+
+// Events may be stored out of order, an older one never replaces a newer one
+const SQL_UPSERT_SEEN: &str = "insert into seen (nick_key, channel, nick, seen, action, other, msg) \
+    values ($1, $2, $3, $4, $5, $6, $7) \
+    on conflict (nick_key, channel) do update \
+    set nick = excluded.nick, seen = excluded.seen, action = excluded.action, \
+    other = excluded.other, msg = excluded.msg \
+    where seen.seen <= excluded.seen";
+
+pub async fn db_upsert_seen(db: &DbCtx, row: &DbSeen) -> anyhow::Result<u64> {
+    let res = sqlx::query(SQL_UPSERT_SEEN)
+        .bind(row.nick.to_lowercase())
+        .bind(row.channel.to_lowercase())
+        .bind(&row.nick)
+        .bind(row.seen)
+        .bind(&row.action)
+        .bind(&row.other)
+        .bind(&row.msg)
+        .execute(&db.dbc)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+const SQL_GET_SEEN: &str = "select channel, nick, seen, action, other, msg \
+    from seen \
+    where nick_key = $1 and channel = any($2) \
+    order by seen desc \
+    limit 1";
+
+// The latest of the given channels
+pub async fn db_get_seen(db: &DbCtx, chans: &[String], nick: &str) -> anyhow::Result<Option<DbSeen>> {
+    debug!("db_get_seen(): {chans:?} {nick:?}");
+    let chans = chans.iter().map(|c| c.to_lowercase()).collect::<Vec<_>>();
+    let res = sqlx::query_as::<_, DbSeen>(SQL_GET_SEEN)
+        .bind(nick.to_lowercase())
+        .bind(chans)
+        .fetch_optional(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbTell {
+    pub id: i64,
+    pub created: i64,
+    pub channel: String,
+    pub sender: String,
+    pub recipient: String,
+    pub msg: String,
+}
+
+// Serializes the adds per sender until the commit, a plain count would let parallel ones through
+const SQL_LOCK_TELL_SENDER: &str = "select pg_advisory_xact_lock(hashtext('tell'), hashtext($1))";
+
+const SQL_INSERT_TELL: &str = "insert into tell (created, channel, sender, sender_key, recipient, recipient_key, msg) \
+    select $1, $2, $3, $4, $5, $6, $7 \
+    where (select count(*) from tell where sender_key = $4) < $8 \
+    returning id";
+
+// Returns the new id, None if the sender already has `max_pending` messages waiting
+pub async fn db_add_tell(db: &DbCtx, tell: &DbTell, max_pending: i64) -> anyhow::Result<Option<i64>> {
+    debug!("db_add_tell({tell:?})");
+    let sender_key = tell.sender.to_lowercase();
+    let mut tx = db.dbc.begin().await?;
+    sqlx::query(SQL_LOCK_TELL_SENDER)
+        .bind(&sender_key)
+        .execute(&mut *tx)
+        .await?;
+    let res = sqlx::query_scalar::<_, i64>(SQL_INSERT_TELL)
+        .bind(tell.created)
+        .bind(tell.channel.to_lowercase())
+        .bind(&tell.sender)
+        .bind(&sender_key)
+        .bind(&tell.recipient)
+        .bind(tell.recipient.to_lowercase())
+        .bind(&tell.msg)
+        .bind(max_pending)
+        .fetch_optional(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(res)
+}
+
+const SQL_TELL_RECIPIENTS: &str = "select distinct recipient_key from tell";
+
+// The lowercased nicks with messages waiting
+pub async fn db_tell_recipients(db: &DbCtx) -> anyhow::Result<HashSet<String>> {
+    let res = sqlx::query_scalar::<_, String>(SQL_TELL_RECIPIENTS)
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res.into_iter().collect())
+}
+
+const SQL_GET_TELLS: &str = "select id, created, channel, sender, recipient, msg \
+    from tell \
+    where recipient_key = $1 \
+    order by id";
+
+// Oldest first, they stay until deleted one by one with db_delete_tell()
+pub async fn db_get_tells(db: &DbCtx, recipient: &str) -> anyhow::Result<Vec<DbTell>> {
+    let res = sqlx::query_as::<_, DbTell>(SQL_GET_TELLS)
+        .bind(recipient.to_lowercase())
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_DELETE_TELL: &str = "delete from tell where id = $1";
+
+pub async fn db_delete_tell(db: &DbCtx, id: i64) -> anyhow::Result<()> {
+    sqlx::query(SQL_DELETE_TELL).bind(id).execute(&db.dbc).await?;
+    Ok(())
+}
+
+const SQL_LIST_TELLS: &str = "select id, created, channel, sender, recipient, msg \
+    from tell \
+    where sender_key = $1 \
+    order by id";
+
+pub async fn db_list_tells(db: &DbCtx, sender: &str) -> anyhow::Result<Vec<DbTell>> {
+    let res = sqlx::query_as::<_, DbTell>(SQL_LIST_TELLS)
+        .bind(sender.to_lowercase())
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_CANCEL_TELL: &str = "delete from tell where id = $1 and sender_key = $2";
+
+// Only the sender can cancel, returns false if there was no such message of theirs
+pub async fn db_cancel_tell(db: &DbCtx, sender: &str, id: i64) -> anyhow::Result<bool> {
+    let res = sqlx::query(SQL_CANCEL_TELL)
+        .bind(id)
+        .bind(sender.to_lowercase())
+        .execute(&db.dbc)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbReminder {
+    pub id: i64,
+    pub created: i64,
+    pub due: i64,
+    pub channel: String,
+    pub nick: String,
+    pub msg: String,
+}
+
+// Serializes the adds per nick until the commit, a plain count would let parallel ones through
+const SQL_LOCK_REMINDER_NICK: &str = "select pg_advisory_xact_lock(hashtext('reminder'), hashtext($1))";
+
+const SQL_INSERT_REMINDER: &str = "insert into reminder (created, due, channel, nick, nick_key, msg) \
+    select $1, $2, $3, $4, $5, $6 \
+    where (select count(*) from reminder where nick_key = $5) < $7 \
+    returning id";
+
+// Returns the new id, None if the nick already has `max_pending` reminders waiting
+pub async fn db_add_reminder(db: &DbCtx, rem: &DbReminder, max_pending: i64) -> anyhow::Result<Option<i64>> {
+    debug!("db_add_reminder({rem:?})");
+    let nick_key = rem.nick.to_lowercase();
+    let mut tx = db.dbc.begin().await?;
+    sqlx::query(SQL_LOCK_REMINDER_NICK)
+        .bind(&nick_key)
+        .execute(&mut *tx)
+        .await?;
+    let res = sqlx::query_scalar::<_, i64>(SQL_INSERT_REMINDER)
+        .bind(rem.created)
+        .bind(rem.due)
+        .bind(rem.channel.to_lowercase())
+        .bind(&rem.nick)
+        .bind(&nick_key)
+        .bind(&rem.msg)
+        .bind(max_pending)
+        .fetch_optional(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(res)
+}
+
+// Channel reminders wait until we are on the channel again
+const SQL_TAKE_DUE_REMINDERS: &str = "delete from reminder \
+    where due <= $1 and (channel = '' or channel = any($2)) \
+    returning id, created, due, channel, nick, msg";
+
+// The reminders due at `now` that can be delivered on `chans`, oldest first
+pub async fn db_take_due_reminders(db: &DbCtx, now: i64, chans: &[String]) -> anyhow::Result<Vec<DbReminder>> {
+    let chans = chans.iter().map(|c| c.to_lowercase()).collect::<Vec<_>>();
+    let mut res = sqlx::query_as::<_, DbReminder>(SQL_TAKE_DUE_REMINDERS)
+        .bind(now)
+        .bind(chans)
+        .fetch_all(&db.dbc)
+        .await?;
+    res.sort_by_key(|r| (r.due, r.id));
+    Ok(res)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbKarma {
+    pub thing: String,
+    pub score: i64,
+}
+
+const SQL_ADD_KARMA: &str = "insert into karma (channel, thing_key, thing, score, updated) \
+    values ($1, $2, $3, $4, $5) \
+    on conflict (channel, thing_key) do update \
+    set thing = excluded.thing, score = karma.score + excluded.score, updated = excluded.updated \
+    returning score";
+
+// Returns the new score
+pub async fn db_add_karma(db: &DbCtx, ts: i64, chan: &str, thing: &str, delta: i64) -> anyhow::Result<i64> {
+    let res = sqlx::query_scalar::<_, i64>(SQL_ADD_KARMA)
+        .bind(chan.to_lowercase())
+        .bind(thing.to_lowercase())
+        .bind(thing)
+        .bind(delta)
+        .bind(ts)
+        .fetch_one(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_GET_KARMA: &str = "select thing, score from karma where channel = $1 and thing_key = $2";
+
+pub async fn db_get_karma(db: &DbCtx, chan: &str, thing: &str) -> anyhow::Result<Option<DbKarma>> {
+    let res = sqlx::query_as::<_, DbKarma>(SQL_GET_KARMA)
+        .bind(chan.to_lowercase())
+        .bind(thing.to_lowercase())
+        .fetch_optional(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_KARMA_TOP: &str = "select thing, score from karma \
+    where channel = $1 and score > 0 \
+    order by score desc, updated desc \
+    limit $2";
+
+const SQL_KARMA_BOTTOM: &str = "select thing, score from karma \
+    where channel = $1 and score < 0 \
+    order by score, updated desc \
+    limit $2";
+
+// The highest scores, or with `bottom` the lowest
+pub async fn db_karma_rank(db: &DbCtx, chan: &str, bottom: bool, limit: i64) -> anyhow::Result<Vec<DbKarma>> {
+    let sql = match bottom {
+        true => SQL_KARMA_BOTTOM,
+        false => SQL_KARMA_TOP,
+    };
+    let res = sqlx::query_as::<_, DbKarma>(sql)
+        .bind(chan.to_lowercase())
+        .bind(limit)
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbQuote {
+    pub id: i64,
+    pub channel: String,
+    pub added: i64,
+    pub nick: String,
+    pub quote: String,
+}
+
+const SQL_INSERT_QUOTE: &str = "insert into quote (channel, added, nick, quote) \
+    values ($1, $2, $3, $4) \
+    returning id";
+
+// Returns the new id
+pub async fn db_add_quote(db: &DbCtx, chan: &str, ts: i64, nick: &str, quote: &str) -> anyhow::Result<i64> {
+    let res = sqlx::query_scalar::<_, i64>(SQL_INSERT_QUOTE)
+        .bind(chan.to_lowercase())
+        .bind(ts)
+        .bind(nick)
+        .bind(quote)
+        .fetch_one(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_GET_QUOTE: &str = "select id, channel, added, nick, quote from quote where channel = $1 and id = $2";
+
+pub async fn db_get_quote(db: &DbCtx, chan: &str, id: i64) -> anyhow::Result<Option<DbQuote>> {
+    let res = sqlx::query_as::<_, DbQuote>(SQL_GET_QUOTE)
+        .bind(chan.to_lowercase())
+        .bind(id)
+        .fetch_optional(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_RANDOM_QUOTE: &str = "select id, channel, added, nick, quote from quote \
+    where channel = $1 \
+    order by random() \
+    limit 1";
+
+pub async fn db_random_quote(db: &DbCtx, chan: &str) -> anyhow::Result<Option<DbQuote>> {
+    let res = sqlx::query_as::<_, DbQuote>(SQL_RANDOM_QUOTE)
+        .bind(chan.to_lowercase())
+        .fetch_optional(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+// strpos() needs no escaping of LIKE wildcards
+const SQL_SEARCH_QUOTE: &str = "select id, channel, added, nick, quote from quote \
+    where channel = $1 and strpos(lower(quote), lower($2)) > 0 \
+    order by id desc \
+    limit $3";
+
+// Newest first
+pub async fn db_search_quote(db: &DbCtx, chan: &str, term: &str, limit: i64) -> anyhow::Result<Vec<DbQuote>> {
+    let res = sqlx::query_as::<_, DbQuote>(SQL_SEARCH_QUOTE)
+        .bind(chan.to_lowercase())
+        .bind(term)
+        .bind(limit)
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_DELETE_QUOTE: &str = "delete from quote where channel = $1 and id = $2";
+
+// Returns false if there was no such quote on the channel
+pub async fn db_del_quote(db: &DbCtx, chan: &str, id: i64) -> anyhow::Result<bool> {
+    let res = sqlx::query(SQL_DELETE_QUOTE)
+        .bind(chan.to_lowercase())
+        .bind(id)
+        .execute(&db.dbc)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbFactoid {
+    pub channel: String,
+    pub key: String,
+    pub value: String,
+    pub nick: String,
+    pub updated: i64,
+    pub locked: bool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbFactoidHistory {
+    pub action: String,
+    pub value: String,
+    pub nick: String,
+    pub ts: i64,
+}
+
+// A channel sorts after the empty global one
+const SQL_GET_FACTOID: &str = "select channel, key, value, nick, updated, locked \
+    from factoid \
+    where channel = any($1) and key = $2 \
+    order by channel desc \
+    limit 1";
+
+// The first match of `chans`, a channel one before the global one ("")
+pub async fn db_get_factoid(db: &DbCtx, chans: &[String], key: &str) -> anyhow::Result<Option<DbFactoid>> {
+    let chans = chans.iter().map(|c| c.to_lowercase()).collect::<Vec<_>>();
+    let res = sqlx::query_as::<_, DbFactoid>(SQL_GET_FACTOID)
+        .bind(chans)
+        .bind(key.to_lowercase())
+        .fetch_optional(&db.dbc)
+        .await?;
+    Ok(res)
+}
+
+const SQL_LEARN_FACTOID: &str = "insert into factoid (channel, key, value, nick, updated) \
+    values ($1, $2, $3, $4, $5) \
+    on conflict (channel, key) do update \
+    set value = excluded.value, nick = excluded.nick, updated = excluded.updated";
+
+const SQL_FORGET_FACTOID: &str = "delete from factoid where channel = $1 and key = $2";
+
+const SQL_LOCK_FACTOID: &str = "update factoid set locked = $3 where channel = $1 and key = $2";
+
+const SQL_INSERT_FACTOID_HISTORY: &str = "insert into factoid_history (channel, key, action, value, nick, ts) \
+    values ($1, $2, $3, $4, $5, $6)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FactoidChange<'a> {
+    Learn(&'a str),
+    Forget,
+    Lock,
+    Unlock,
+}
+
+// Applies the change and records it, returns false if there was no such factoid to change
+pub async fn db_change_factoid(
+    db: &DbCtx,
+    chan: &str,
+    key: &str,
+    change: FactoidChange<'_>,
+    nick: &str,
+    ts: i64,
+) -> anyhow::Result<bool> {
+    debug!("db_change_factoid(): {chan:?} {key:?} {change:?} by {nick}");
+    let (chan, key) = (chan.to_lowercase(), key.to_lowercase());
+    let mut tx = db.dbc.begin().await?;
+    let (query, action, value) = match change {
+        FactoidChange::Learn(value) => (
+            sqlx::query(SQL_LEARN_FACTOID)
+                .bind(&chan)
+                .bind(&key)
+                .bind(value)
+                .bind(nick)
+                .bind(ts),
+            "learn",
+            value,
+        ),
+        FactoidChange::Forget => (sqlx::query(SQL_FORGET_FACTOID).bind(&chan).bind(&key), "forget", ""),
+        FactoidChange::Lock | FactoidChange::Unlock => (
+            sqlx::query(SQL_LOCK_FACTOID)
+                .bind(&chan)
+                .bind(&key)
+                .bind(change == FactoidChange::Lock),
+            match change {
+                FactoidChange::Lock => "lock",
+                _ => "unlock",
+            },
+            "",
+        ),
+    };
+    if query.execute(&mut *tx).await?.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sqlx::query(SQL_INSERT_FACTOID_HISTORY)
+        .bind(&chan)
+        .bind(&key)
+        .bind(action)
+        .bind(value)
+        .bind(nick)
+        .bind(ts)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+const SQL_FACTOID_HISTORY: &str = "select action, value, nick, ts \
+    from factoid_history \
+    where channel = $1 and key = $2 \
+    order by id desc \
+    limit $3";
+
+// Newest first
+pub async fn db_factoid_history(
+    db: &DbCtx,
+    chan: &str,
+    key: &str,
+    limit: i64,
+) -> anyhow::Result<Vec<DbFactoidHistory>> {
+    let res = sqlx::query_as::<_, DbFactoidHistory>(SQL_FACTOID_HISTORY)
+        .bind(chan.to_lowercase())
+        .bind(key.to_lowercase())
+        .bind(limit)
+        .fetch_all(&db.dbc)
+        .await?;
+    Ok(res)
+}
 // EOF
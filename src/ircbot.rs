@@ -2,33 +2,159 @@
 
 use chrono_tz::Tz;
 use futures::{future::BoxFuture, prelude::*};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::atomic::{AtomicBool, Ordering as AtomicOrdering},
+    time::Instant,
+};
 use tera::{Kwargs, State, Tera};
 
+use tokio::sync::{Mutex, Semaphore, mpsc::error::SendTimeoutError};
+
 use crate::*;
 
 const INITIAL_HANDLERS: usize = 8;
+const RECONNECT_QUIT_REASON: &str = "Reconnecting";
 
 // in milliseconds
-const IRC_OP_THROTTLE: u64 = 2500;
-const IRC_OP_THROTTLE_JITTER: u64 = 1000;
-const IRC_MSG_THROTTLE: u64 = 1500;
-const IRC_QUEUE_CAPACITY: usize = 42;
 const IRC_QUEUE_SEND_TIMEOUT: u64 = 5000;
+const HANDLER_TIMEOUT: u64 = 30000;
+const HANDLER_WORKER_IDLE: u64 = 60000;
+// how long the read loop waits for room in a full handler queue before dropping
+const HANDLER_QUEUE_WAIT: u64 = 1000;
+
+// messages are handled in per-channel workers, at most this many at a time
+const HANDLER_MAX_INFLIGHT: usize = 16;
+// a PRIVMSG takes two items, its log event and the message itself
+const HANDLER_QUEUE_CAPACITY: usize = 2 * 32;
+// our own sent messages waiting to be handed to the plugins
+const OWN_EVENTS_CAPACITY: usize = 64;
 
-pub type CmdHandler = Box<dyn Fn(Arc<IrcBot>, Command) -> BoxFuture<'static, anyhow::Result<bool>>>;
+pub type CmdHandler =
+    Arc<dyn Fn(Arc<IrcBot>, MsgContext, Command) -> BoxFuture<'static, anyhow::Result<bool>> + Send + Sync>;
 
 pub fn into_cmd_handler<Fut: Future<Output = anyhow::Result<bool>> + Send + 'static>(
-    f: impl Fn(Arc<IrcBot>, Command) -> Fut + Send + 'static,
+    f: impl Fn(Arc<IrcBot>, MsgContext, Command) -> Fut + Send + Sync + 'static,
 ) -> CmdHandler {
-    Box::new(move |bot, c| Box::pin(f(bot, c)))
+    Arc::new(move |bot, ctx, c| Box::pin(f(bot, ctx, c)))
 }
 
-pub type MsgHandler = Box<dyn Fn(Arc<IrcBot>, String, String, String) -> BoxFuture<'static, anyhow::Result<bool>>>;
+pub type MsgHandler = Arc<dyn Fn(Arc<IrcBot>, MsgContext) -> BoxFuture<'static, anyhow::Result<bool>> + Send + Sync>;
 
 pub fn into_msg_handler<Fut: Future<Output = anyhow::Result<bool>> + Send + 'static>(
-    f: impl Fn(Arc<IrcBot>, String, String, String) -> Fut + Send + 'static,
+    f: impl Fn(Arc<IrcBot>, MsgContext) -> Fut + Send + Sync + 'static,
 ) -> MsgHandler {
-    Box::new(move |bot, a, b, c| Box::pin(f(bot, a, b, c)))
+    Arc::new(move |bot, ctx| Box::pin(f(bot, ctx)))
+}
+
+// Everything a handler needs to know about the message it was called for.
+// This is built once per incoming message and handed over by value, so concurrent
+// handlers can never observe the sender of some later message.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MsgContext {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub userhost: String,
+    // services account name, from the `account` tag or extended-join
+    pub account: Option<String>,
+    // my own nick at the time the message was received
+    pub my_nick: String,
+    // the raw target parameter, e.g. a channel or my own nick
+    pub target: String,
+    // set if the message was sent to (or is about) a channel
+    pub channel: Option<String>,
+    #[serde(serialize_with = "serialize_tags")]
+    pub tags: Vec<Tag>,
+
+    // PRIVMSG text, split into the first word and the rest
+    pub msg: String,
+    pub cmd: String,
+    pub args: String,
+    // urls found in a channel message, blacklisted ones already left out
+    pub urls: Vec<String>,
+}
+
+fn serialize_tags<S: serde::Serializer>(tags: &[Tag], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(tags.iter().map(|Tag(k, v)| (k, v)))
+}
+
+impl MsgContext {
+    pub fn new(message: &Message, my_nick: &str) -> Self {
+        let (nick, user, host) = match &message.prefix {
+            Some(Prefix::Nickname(nick, user, host)) => (nick.clone(), user.clone(), host.clone()),
+            _ => ("NONE".into(), "NONE".into(), "NONE".into()),
+        };
+        let tags = message.tags.clone().unwrap_or_default();
+        let mut account = tags
+            .iter()
+            .find(|Tag(k, _)| k == "account")
+            .and_then(|Tag(_, v)| v.clone());
+
+        let mut ctx = Self {
+            userhost: format!("{user}@{host}"),
+            nick,
+            user,
+            host,
+            my_nick: my_nick.to_string(),
+            tags,
+            ..Default::default()
+        };
+
+        match &message.command {
+            Command::PRIVMSG(target, msg) | Command::NOTICE(target, msg) => {
+                let (cmd, args) = match msg.split_once(|c: char| c.is_whitespace()) {
+                    Some((c, a)) => (c.to_string(), a.to_string()),
+                    None => (msg.clone(), "".to_string()),
+                };
+                ctx.target = target.clone();
+                if !target.eq_ignore_ascii_case(my_nick) {
+                    ctx.channel = Some(target.clone());
+                }
+                ctx.msg = msg.clone();
+                ctx.cmd = cmd;
+                ctx.args = args;
+            }
+            Command::JOIN(channel, ext_account, _) => {
+                // with extended-join the account is the second parameter, "*" if logged out
+                if account.is_none()
+                    && let Some(a) = ext_account.as_ref().filter(|a| a.as_str() != "*")
+                {
+                    account = Some(a.clone());
+                }
+                ctx.target = channel.clone();
+                ctx.channel = Some(channel.clone());
+            }
+            Command::PART(channel, _)
+            | Command::KICK(channel, _, _)
+            | Command::TOPIC(channel, _)
+            | Command::ChannelMODE(channel, _) => {
+                ctx.target = channel.clone();
+                ctx.channel = Some(channel.clone());
+            }
+            _ => {}
+        }
+
+        ctx.account = account;
+        ctx
+    }
+
+    // Where a reply should go: the channel for channel messages, the sender otherwise
+    pub fn reply_target(&self) -> &str {
+        self.channel.as_deref().unwrap_or(&self.nick)
+    }
+
+    pub async fn reply(&self, bot: &Arc<IrcBot>, msg: &str) -> anyhow::Result<bool> {
+        bot.clone().new_msg(self.reply_target(), msg).await
+    }
+
+    // Used for replies to privileged commands, so they go before the automatic output
+    pub async fn reply_nick(&self, bot: &Arc<IrcBot>, msg: &str) -> anyhow::Result<bool> {
+        bot.outbound
+            .send(OutPriority::High, OutKind::Privmsg, &self.nick, msg)
+            .await?;
+        Ok(true)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,22 +164,18 @@ pub enum IrcOp {
     Invite(String, String),
     Nick(String),
     Join(String),
-    UrlCheck(DbCtx, String, String, Tz, i64),
-    UrlTitle(String, String),
-    UrlLog(DbCtx, String, String, String, i64),
-    UrlFetch(String, String, Regex),
+    Part(String),
 }
 
-#[derive(Debug, Clone)]
-struct IrcMsg {
-    target: String,
-    msg: String,
-}
-
-#[derive(Debug, Clone)]
-enum QueuedAction {
-    Msg(IrcMsg),
-    Op(IrcOp),
+impl IrcOp {
+    // The channel the op is about, for outbound accounting
+    pub fn target(&self) -> &str {
+        match self {
+            IrcOp::ModeVoice(channel, _) | IrcOp::ModeOper(channel, _) | IrcOp::Invite(_, channel) => channel,
+            IrcOp::Join(channel) | IrcOp::Part(channel) => channel,
+            IrcOp::Nick(_) => "*",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -106,7 +228,7 @@ fn is_tracked_user_mode(mode: &ChannelMode) -> bool {
 }
 
 #[derive(Debug, Default)]
-struct ChannelModes {
+pub(crate) struct ChannelModes {
     users: HashMap<String, HashMap<String, ChannelUserModes>>,
 }
 
@@ -118,6 +240,21 @@ impl ChannelModes {
             .is_some_and(|modes| modes.oper)
     }
 
+    fn channels_of(&self, nick: &str) -> Vec<String> {
+        self.users
+            .iter()
+            .filter(|(_, users)| users.contains_key(nick))
+            .map(|(channel, _)| channel.clone())
+            .collect()
+    }
+
+    // users and operators on a channel
+    fn counts(&self, channel: &str) -> (usize, usize) {
+        self.users.get(channel).map_or((0, 0), |users| {
+            (users.len(), users.values().filter(|modes| modes.oper).count())
+        })
+    }
+
     fn mark_oper(&mut self, channel: &str, nick: &str) {
         self.users
             .entry(channel.to_string())
@@ -254,6 +391,13 @@ pub struct UrlCmd {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BotConfig {
     pub irc_log_dir: String,
+    // channels and query nicks to log, see irclog.rs
+    #[serde(default)]
+    pub irc_log_channels: HashMap<String, bool>,
+    #[serde(default)]
+    pub irc_log_format: HashMap<String, LogFormat>,
+    #[serde(default)]
+    pub irc_log_gzip: bool,
     pub channel: String,
     pub privileged_nicks: HashMap<String, bool>,
 
@@ -285,6 +429,14 @@ pub struct BotConfig {
     pub cmd_reload: String,
     // say something to a channel
     pub cmd_say: String,
+    #[serde(default = "default_cmd_status")]
+    pub cmd_status: String,
+    // leave a channel
+    #[serde(default = "default_cmd_part")]
+    pub cmd_part: String,
+    // drop the connection and reconnect
+    #[serde(default = "default_cmd_reconnect")]
+    pub cmd_reconnect: String,
     // Regex list for +o ACL
     pub mode_o_acl: Vec<String>,
     // Regex list for auto-op ACL
@@ -296,6 +448,43 @@ pub struct BotConfig {
     pub url_cmd_list: HashMap<String, UrlCmd>,
     pub url_mut_list: Vec<(String, String)>,
 
+    #[serde(default)]
+    pub scripts: BTreeMap<String, ScriptCmd>,
+    #[serde(default)]
+    pub wasm_plugins: WasmPluginConfig,
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub lag: LagConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub url_list: PluginConfig<UrlListConfig>,
+    #[serde(default)]
+    pub history: PluginConfig<HistoryConfig>,
+    #[serde(default)]
+    pub seen: PluginConfig<SeenConfig>,
+    #[serde(default)]
+    pub tell: PluginConfig<TellConfig>,
+    #[serde(default)]
+    pub remind: PluginConfig<RemindConfig>,
+    #[serde(default)]
+    pub karma: PluginConfig<KarmaConfig>,
+    #[serde(default)]
+    pub quote: PluginConfig<QuoteConfig>,
+    #[serde(default)]
+    pub factoid: PluginConfig<FactoidConfig>,
+    // Unix socket for `sjmb ctl`, empty disables it
+    #[serde(default)]
+    pub control_socket: String,
+    #[serde(default = "default_quit_reason")]
+    pub quit_reason: String,
+    // in milliseconds, how long to wait for queued output and URL jobs when shutting down
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+
     #[serde(skip)]
     pub mode_o_acl_rt: Option<ReAcl>,
     #[serde(skip)]
@@ -316,7 +505,37 @@ pub struct BotConfig {
     #[serde(skip)]
     pub db: Option<DbCtx>,
 }
+
+fn default_cmd_status() -> String {
+    "status".to_string()
+}
+
+fn default_cmd_part() -> String {
+    "part".to_string()
+}
+
+fn default_cmd_reconnect() -> String {
+    "reconnect".to_string()
+}
+
+fn default_quit_reason() -> String {
+    "Shutting down".to_string()
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10000
+}
+
 impl BotConfig {
+    // The display timezone of a channel, from url_dup_timezone
+    pub fn channel_tz(&self, channel: &str) -> Tz {
+        self.url_dup_tz
+            .as_ref()
+            .and_then(|tz| get_wild(tz, channel))
+            .copied()
+            .unwrap_or(Tz::UTC)
+    }
+
     pub fn new(config_file: &str) -> anyhow::Result<Self> {
         let now1 = Utc::now();
 
@@ -326,6 +545,7 @@ impl BotConfig {
         // Expand $HOME where relevant
         config.irc_log_dir = shellexpand::full(&config.irc_log_dir)?.into_owned();
         config.url_log_db = shellexpand::full(&config.url_log_db)?.into_owned();
+        config.control_socket = shellexpand::full(&config.control_socket)?.into_owned();
 
         // read & parse ACLs ()
         config.mode_o_acl_rt = Some(ReAcl::new(&config.mode_o_acl)?);
@@ -380,15 +600,34 @@ pub struct BotHandlers {
     handlers_chanmsg: HashMap<String, MsgHandler>,
 }
 
+#[derive(Default)]
 pub struct BotState {
     pub my_nick: String,
-    pub msg_nick: String,
-    pub msg_user: String,
-    pub msg_host: String,
-    pub msg_userhost: String,
+    // channels we are on, rejoined after a reconnect
+    pub channels: BTreeSet<String>,
+    // joined by the irc crate itself on every connect
+    pub auto_channels: Vec<String>,
+    // the last ERROR or ban numeric from the server, tells why we were disconnected
+    pub last_error: Option<String>,
+    pub server: String,
+    pub connected_since: Option<DateTime<Utc>>,
+    // set on RPL_WELCOME
+    pub registered: bool,
+    pub lag: LagMonitor,
+}
+
+impl BotState {
+    // One line for systemd
+    fn summary(&self) -> String {
+        format!("{} on {}, {} channels", self.my_nick, self.server, self.channels.len())
+    }
+}
 
-    op_sender: mpsc::Sender<IrcOp>,
-    msg_sender: mpsc::Sender<IrcMsg>,
+// What a handler worker processes, in the order it arrived
+#[derive(Debug)]
+enum Work {
+    Message(Box<MsgContext>, Command),
+    Event(LogEvent),
 }
 
 pub struct IrcBot {
@@ -396,14 +635,25 @@ pub struct IrcBot {
     pub config: RwLock<BotConfig>,
     pub state: RwLock<BotState>,
     pub handlers: RwLock<BotHandlers>,
+    pub plugins: RwLock<Vec<Arc<dyn BotPlugin>>>,
+    pub outbound: Outbound,
+    pub url_workers: UrlWorkers,
+    pub systemd: SystemdNotify,
+    pub irc_log: IrcLogger,
     channel_modes: Arc<RwLock<ChannelModes>>,
+    shutting_down: AtomicBool,
+
+    // per-channel (or per-nick for private messages) handler queues
+    workers: Mutex<HashMap<String, mpsc::Sender<Work>>>,
+    // taken by the first run()
+    own_events: Mutex<Option<mpsc::Receiver<LogEvent>>>,
+    handler_permits: Arc<Semaphore>,
+    ticking: Arc<Mutex<()>>,
 }
 
-unsafe impl Send for IrcBot {}
-unsafe impl Sync for IrcBot {}
-
 impl IrcBot {
-    pub async fn new(opts: &OptsCommon) -> anyhow::Result<(Self, irc::client::ClientStream)> {
+    // The bot outlives its connections, see connect()
+    pub async fn new(opts: &OptsCommon) -> anyhow::Result<Self> {
         let mut bot_cfg = match BotConfig::new(&opts.bot_config) {
             Ok(b) => b,
             Err(e) => {
@@ -412,58 +662,197 @@ impl IrcBot {
         };
         bot_cfg.db = Some(start_db(&bot_cfg.url_log_db).await?);
 
-        let mut irc = match Client::new(&opts.irc_config).await {
-            Ok(c) => c,
-            Err(e) => {
-                bail!("{e}");
+        let channel_modes = Arc::new(RwLock::new(ChannelModes::default()));
+        let irc_log = IrcLogger::start(IrcLogConfig::new(&bot_cfg));
+        let (own_events, own_events_rx) = mpsc::channel(OWN_EVENTS_CAPACITY);
+        let outbound = Outbound::start(
+            channel_modes.clone(),
+            bot_cfg.outbound.clone(),
+            irc_log.clone(),
+            own_events,
+        );
+        let url_workers = UrlWorkers::start(outbound.clone());
+
+        Ok(IrcBot {
+            cli_opts: RwLock::new(opts.clone()),
+            config: RwLock::new(bot_cfg),
+            state: RwLock::new(BotState::default()),
+            handlers: RwLock::new(BotHandlers {
+                handlers_irc_cmd: Vec::with_capacity(INITIAL_HANDLERS),
+                handlers_privmsg_open: HashMap::with_capacity(INITIAL_HANDLERS),
+                handlers_privmsg_priv: HashMap::with_capacity(INITIAL_HANDLERS),
+                handlers_chanmsg: HashMap::with_capacity(INITIAL_HANDLERS),
+            }),
+            plugins: RwLock::new(Vec::with_capacity(INITIAL_HANDLERS)),
+            outbound,
+            url_workers,
+            systemd: SystemdNotify::from_env(),
+            irc_log,
+            channel_modes,
+            shutting_down: AtomicBool::new(false),
+            workers: Mutex::new(HashMap::new()),
+            own_events: Mutex::new(Some(own_events_rx)),
+            handler_permits: Arc::new(Semaphore::new(HANDLER_MAX_INFLIGHT)),
+            ticking: Arc::new(Mutex::new(())),
+        })
+    }
+
+    // Opens a new connection, to `server` instead of the one in the irc config if given.
+    // Handlers, queues and the database pool are kept, per-connection state starts over.
+    pub async fn connect(&self, server: Option<&ServerAddr>) -> anyhow::Result<irc::client::ClientStream> {
+        let irc_config = self.cli_opts.read().await.irc_config.clone();
+        let mut config = Config::load(&irc_config)?;
+        if let Some(server) = server {
+            config.server = Some(server.host.clone());
+            if server.port.is_some() {
+                config.port = server.port;
             }
-        };
-        if let Err(e) = irc.identify() {
-            bail!("{e}");
         }
+        let server = format!(
+            "{}:{}",
+            config.server.as_deref().unwrap_or_default(),
+            config.port.unwrap_or_default()
+        );
+        info!("Connecting to {server}");
+        let auto_channels = config.channels.clone();
+
+        let mut irc = Client::from_config(config).await?;
+        irc.identify()?;
 
         let my_nick = irc.current_nickname().to_string();
-        let irc_sender1 = Arc::new(irc.sender());
-        let irc_sender2 = irc_sender1.clone();
-        let channel_modes = Arc::new(RwLock::new(ChannelModes::default()));
-        let op_channel_modes = channel_modes.clone();
+        *self.channel_modes.write().await = ChannelModes::default();
+        {
+            let mut state = self.state.write().await;
+            state.my_nick = my_nick.clone();
+            state.auto_channels = auto_channels;
+            state.last_error = None;
+            state.server = server;
+            state.connected_since = Some(Utc::now());
+            state.registered = false;
+            state.lag = LagMonitor::default();
+        }
+        // the queued output waits for RPL_WELCOME, see run()
+        self.outbound.attach(irc.sender());
+        Ok(irc.stream()?)
+    }
 
-        let (op_sender, op_rx) = mpsc::channel::<IrcOp>(IRC_QUEUE_CAPACITY);
-        tokio::spawn(async move {
-            debug!("Starting op queue receiver");
-            read_op_queue(irc_sender1, op_channel_modes, op_rx).await;
-        });
+    // Sends our own PINGs, and gives up on a connection that has gone quiet or laggy
+    async fn check_lag(&self) -> anyhow::Result<()> {
+        let cfg = self.config.read().await.lag.clone();
+        let action = self.state.write().await.lag.tick(&cfg, Instant::now());
+        match action {
+            LagAction::Nothing => Ok(()),
+            LagAction::Ping(token) => self.outbound.send_raw(Command::PING(token, None)),
+            LagAction::Reconnect(reason) => bail!("Connection is stale: {reason}"),
+        }
+    }
 
-        let (msg_sender, msg_rx) = mpsc::channel::<IrcMsg>(IRC_QUEUE_CAPACITY);
-        tokio::spawn(async move {
-            debug!("Starting msg queue receiver");
-            read_msg_queue(irc_sender2, msg_rx).await;
-        });
+    // Only reached while the lag monitor is happy, see run()
+    async fn notify_systemd(&self) {
+        let state = self.state.read().await;
+        if state.registered {
+            self.systemd.watchdog();
+            self.systemd.status(&state.summary());
+        }
+    }
+
+    // Allowed to use the privileged commands
+    pub async fn is_privileged(&self, nick: &str) -> bool {
+        matches!(self.config.read().await.privileged_nicks.get(nick), Some(true))
+    }
+
+    // The channels we have seen `nick` on
+    pub async fn channels_of(&self, nick: &str) -> Vec<String> {
+        self.channel_modes.read().await.channels_of(nick)
+    }
+
+    // Human readable lines for the status command
+    pub async fn status(&self) -> Vec<String> {
+        let (server, since, lag, nick, channels) = {
+            let state = self.state.read().await;
+            (
+                state.server.clone(),
+                state.connected_since,
+                state.lag.lag(Instant::now()),
+                state.my_nick.clone(),
+                state.channels.iter().cloned().collect::<Vec<_>>(),
+            )
+        };
+        let uptime = since.map_or_else(
+            || "not connected".to_string(),
+            |since| format!("connected {}", (Utc::now() - since).num_seconds().human_duration()),
+        );
+        let lag = lag.map_or_else(|| "unknown".to_string(), |lag| format!("{} ms", lag.as_millis()));
+        let mut lines = vec![
+            format!("{nick} on {server}, {uptime}, lag {lag}"),
+            format!("Channels: {}", channels.join(" ")),
+            format!(
+                "Outbound queue: {} waiting, URL jobs: {} pending",
+                self.outbound.pending(),
+                self.url_workers.pending()
+            ),
+        ];
+        let channel_modes = self.channel_modes.read().await;
+        for channel in &channels {
+            let (users, ops) = channel_modes.counts(channel);
+            let me = match channel_modes.has_oper(channel, &nick) {
+                true => "opped",
+                false => "not opped",
+            };
+            lines.push(format!("{channel}: {users} users, {ops} ops, {me}"));
+        }
+        lines
+    }
+
+    // QUIT now, the run loop ends when the server closes the link and the usual
+    // reconnect follows
+    pub fn request_reconnect(&self) -> anyhow::Result<()> {
+        self.outbound.quit(RECONNECT_QUIT_REASON)
+    }
+
+    // Call when the connection is gone, output waits for the next one
+    pub async fn disconnected(&self) -> Disconnect {
+        self.outbound.disconnected();
+        let reason = Disconnect::classify(self.state.read().await.last_error.as_deref());
+        let label = format!("{reason:?}").to_lowercase();
+        metrics().inc("sjmb_reconnects_total", &[("reason", &label)]);
+        reason
+    }
 
-        Ok((
-            IrcBot {
-                cli_opts: RwLock::new(opts.clone()),
-                config: RwLock::new(bot_cfg),
-                state: RwLock::new(BotState {
-                    my_nick,
-                    msg_nick: "NONE".into(),
-                    msg_user: "NONE".into(),
-                    msg_host: "NONE".into(),
-                    msg_userhost: "NONE@NONE".into(),
-
-                    op_sender,
-                    msg_sender,
-                }),
-                handlers: RwLock::new(BotHandlers {
-                    handlers_irc_cmd: Vec::with_capacity(INITIAL_HANDLERS),
-                    handlers_privmsg_open: HashMap::with_capacity(INITIAL_HANDLERS),
-                    handlers_privmsg_priv: HashMap::with_capacity(INITIAL_HANDLERS),
-                    handlers_chanmsg: HashMap::with_capacity(INITIAL_HANDLERS),
-                }),
-                channel_modes,
-            },
-            irc.stream()?,
-        ))
+    // Refreshes the gauges that mirror bot state, before a scrape
+    pub async fn update_metrics(&self) {
+        let m = metrics();
+        m.set(
+            "sjmb_queue_depth",
+            &[("queue", "outbound")],
+            self.outbound.pending() as f64,
+        );
+        m.set(
+            "sjmb_queue_depth",
+            &[("queue", "url")],
+            self.url_workers.pending() as f64,
+        );
+        if let Some(lag) = self.state.read().await.lag.lag(Instant::now()) {
+            m.set("sjmb_lag_seconds", &[], lag.as_secs_f64());
+        }
+    }
+
+    // Joins the channels we were on before a reconnect, except the ones the irc crate joins anyway
+    async fn rejoin_channels(self: Arc<Self>) -> anyhow::Result<()> {
+        let channels = {
+            let state = self.state.read().await;
+            state
+                .channels
+                .iter()
+                .filter(|c| !state.auto_channels.iter().any(|a| a.eq_ignore_ascii_case(c)))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        for channel in channels {
+            info!("Rejoining {channel}");
+            self.clone().new_op(IrcOp::Join(channel)).await?;
+        }
+        Ok(())
     }
 
     pub async fn clear_handlers(&self) {
@@ -472,15 +861,26 @@ impl IrcBot {
         handlers.handlers_privmsg_open.clear();
         handlers.handlers_privmsg_priv.clear();
         handlers.handlers_chanmsg.clear();
+        self.plugins.write().await.clear();
     }
 
-    pub async fn reload(&self) -> anyhow::Result<bool> {
+    pub async fn add_plugin(self: &Arc<Self>, plugin: Arc<dyn BotPlugin>) -> anyhow::Result<()> {
+        info!("Adding plugin {}", plugin.name());
+        plugin.register(self).await?;
+        self.plugins.write().await.push(plugin);
+        Ok(())
+    }
+
+    pub async fn reload(self: Arc<Self>) -> anyhow::Result<bool> {
         let config_file = self.cli_opts.read().await.bot_config.clone();
         match BotConfig::new(&config_file) {
             Ok(mut cfg) => {
                 cfg.db = Some(start_db(&cfg.url_log_db).await?);
                 info!("*** Reload successful.");
+                self.outbound.set_config(cfg.outbound.clone());
+                self.irc_log.set_config(IrcLogConfig::new(&cfg));
                 *self.config.write().await = cfg;
+                self.plugins_reload().await;
                 Ok(true)
             }
             Err(e) => {
@@ -492,6 +892,38 @@ impl IrcBot {
         }
     }
 
+    // Stops handling messages, waits for the URL jobs and queued output up to the
+    // configured deadline, quits and closes the database pool. The connection has to
+    // keep running meanwhile, since the stream is what writes our output.
+    pub async fn shutdown(&self) {
+        self.shutting_down.store(true, AtomicOrdering::SeqCst);
+        self.systemd.stopping();
+        let (reason, timeout) = {
+            let cfg = self.config.read().await;
+            (cfg.quit_reason.clone(), cfg.shutdown_timeout)
+        };
+        let deadline = Instant::now() + Duration::from_millis(timeout);
+
+        if !self.url_workers.wait_idle(deadline).await {
+            error!("URL jobs still pending at shutdown");
+        }
+        if !self.outbound.flush(deadline).await {
+            error!("Outbound messages still queued at shutdown");
+        }
+        if let Err(e) = self.outbound.quit(&reason) {
+            error!("{e}");
+        }
+        self.close_db().await;
+    }
+
+    pub async fn close_db(&self) {
+        let db = self.config.read().await.db.clone();
+        if let Some(db) = db {
+            db.dbc.close().await;
+            info!("Database pool closed");
+        }
+    }
+
     pub async fn register_irc_cmd(&self, handler: CmdHandler) {
         self.handlers.write().await.handlers_irc_cmd.push(handler);
     }
@@ -520,77 +952,337 @@ impl IrcBot {
             .insert(cmd.to_string(), handler);
     }
 
+    pub async fn unregister_privmsg_open(&self, cmd: &str) {
+        self.handlers.write().await.handlers_privmsg_open.remove(cmd);
+    }
+
+    pub async fn unregister_chanmsg(&self, cmd: &str) {
+        self.handlers.write().await.handlers_chanmsg.remove(cmd);
+    }
+
     pub async fn run(self: Arc<Self>, mut stream: irc::client::ClientStream) -> anyhow::Result<()> {
-        while let Some(message) = stream.next().await.transpose()? {
+        let mut tick = tokio::time::interval(Duration::from_millis(PLUGIN_TICK_INTERVAL));
+        // what we say goes to the plugins through the same workers as what others say
+        if let Some(mut own_events) = self.own_events.lock().await.take() {
+            let bot = self.clone();
+            tokio::spawn(async move {
+                while let Some(event) = own_events.recv().await {
+                    bot.dispatch_event(event).await;
+                }
+            });
+        }
+        loop {
+            let message = tokio::select! {
+                message = stream.next() => match message.transpose()? {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = tick.tick() => {
+                    self.check_lag().await?;
+                    self.notify_systemd().await;
+                    // a slow tick is skipped rather than piled up
+                    if let Ok(guard) = self.ticking.clone().try_lock_owned() {
+                        let bot = self.clone();
+                        tokio::spawn(async move {
+                            bot.plugins_tick().await;
+                            drop(guard);
+                        });
+                    }
+                    continue;
+                }
+            };
             trace!("Got msg: {message:?}");
 
             // `Client::current_nickname()` is read before the stream processes a startup 433 and selects an
             // alternate nick. The welcome response is the server's authoritative registration nick.
             let welcome_nick = welcome_nickname(&message.command).map(str::to_owned);
-            let (msg_nick, msg_user, msg_host) = if let Some(Prefix::Nickname(nick, user, host)) = message.prefix {
-                (nick, user, host)
-            } else {
-                ("NONE".into(), "NONE".into(), "NONE".into())
-            };
+            let connected = welcome_nick.is_some();
 
             let my_nick = {
                 let mut state = self.state.write().await;
-                state.msg_nick = msg_nick.clone();
-                state.msg_user = msg_user.clone();
-                state.msg_host = msg_host.clone();
-                state.msg_userhost = format!("{msg_user}@{msg_host}");
+                let now = Instant::now();
+                state.lag.on_traffic(now);
+                if let Command::PONG(server, token) = &message.command {
+                    state.lag.on_pong(token.as_deref().unwrap_or(server), now);
+                }
                 if let Some(welcome_nick) = welcome_nick {
                     if state.my_nick != welcome_nick {
                         info!("Server accepted alternate nick: {welcome_nick}");
                     }
+                    // what we were on and what the irc crate joins by itself
+                    let rejoining = state
+                        .channels
+                        .iter()
+                        .chain(&state.auto_channels)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    self.outbound.connected(&welcome_nick, &rejoining);
                     state.my_nick = welcome_nick;
+                    state.registered = true;
+                    self.systemd.ready(&state.summary());
                 }
                 state.my_nick.clone()
             };
 
-            self.track_channel_modes(&message.command, &msg_nick, &my_nick).await;
+            // Bot state is tracked here in order, everything else is handed over to the workers
+            let ctx = MsgContext::new(&message, &my_nick);
+            for event in self.irc_events(&message.command, &ctx, &my_nick).await {
+                self.irc_log.log(event.clone());
+                self.dispatch_event(event).await;
+            }
+            self.track_channel_modes(&message.command, &ctx.nick, &my_nick).await;
+
+            if connected {
+                tokio::spawn(self.clone().plugins_connect());
+                let bot = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = bot.rejoin_channels().await {
+                        error!("{e}");
+                    }
+                });
+            }
 
-            for c in self.handlers.read().await.handlers_irc_cmd.iter() {
-                if let Ok(true) = c(self.clone(), message.command.clone()).await {
-                    break;
+            match &message.command {
+                // our displayed host changed, e.g. a cloak was applied
+                Command::Response(Response::RPL_HOSTHIDDEN, v) if v.len() > 1 => {
+                    debug!("My host is now {}", v[1]);
+                    self.outbound.set_host(&v[1]);
+                }
+
+                // the prefix of our own JOIN is how others see us
+                Command::JOIN(channel, ..) if ctx.nick == my_nick => {
+                    self.outbound.set_user(&ctx.user);
+                    self.outbound.set_host(&ctx.host);
+                    self.outbound.joined(channel);
+                    self.state.write().await.channels.insert(channel.to_lowercase());
+                }
+
+                Command::PART(channel, _) if ctx.nick == my_nick => {
+                    self.state.write().await.channels.remove(&channel.to_lowercase());
+                }
+
+                // we do not force our way back after a kick
+                Command::KICK(channel, nick, _) if *nick == my_nick => {
+                    self.state.write().await.channels.remove(&channel.to_lowercase());
+                }
+
+                Command::ERROR(reason) => {
+                    error!("Server closing the link: {reason}");
+                    self.state.write().await.last_error = Some(reason.clone());
+                }
+
+                Command::Response(Response::ERR_YOUREBANNEDCREEP, v) => {
+                    let reason = v.last().cloned().unwrap_or_default();
+                    error!("Banned from the server: {reason}");
+                    self.state.write().await.last_error = Some(format!("banned: {reason}"));
                 }
-            }
 
-            match message.command {
                 Command::Response(resp, v) => {
                     debug!("Got response type {resp:?} contents: {v:?}");
                 }
 
-                Command::PRIVMSG(channel, msg) => {
-                    let (cmd, args) = match msg.split_once(|c: char| c.is_whitespace()) {
-                        Some((c, a)) => (c.to_string(), a.to_string()),
-                        None => (msg.clone(), "".to_string()),
-                    };
+                Command::NICK(new_nick) => {
+                    debug!(
+                        "NICK: {} USER: {} HOST: {} NEW NICK: {new_nick}",
+                        ctx.nick, ctx.user, ctx.host
+                    );
+                    if ctx.nick == my_nick {
+                        info!("My NEW nick: {new_nick}");
+                        self.outbound.set_nick(new_nick);
+                        self.state.write().await.my_nick = new_nick.clone();
+                    }
+                }
+
+                _ => {}
+            }
+
+            if let Command::PRIVMSG(..) = &message.command {
+                let channel = ctx.channel.as_deref().unwrap_or("(private)").to_lowercase();
+                metrics().inc("sjmb_messages_received_total", &[("channel", &channel)]);
+            }
+
+            self.dispatch(ctx, message.command).await;
+        }
+
+        Ok(())
+    }
+
+    // Queue the message to the worker of its channel, the read loop waits at most
+    // HANDLER_QUEUE_WAIT for a busy one
+    async fn dispatch(self: &Arc<Self>, ctx: MsgContext, cmd: Command) {
+        let key = ctx.channel.as_deref().unwrap_or(&ctx.nick).to_lowercase();
+        self.dispatch_work(key, Work::Message(Box::new(ctx), cmd)).await;
+    }
+
+    // Events go through the same queue, so plugins see them in order with the messages
+    async fn dispatch_event(self: &Arc<Self>, event: LogEvent) {
+        let key = event.target.to_lowercase();
+        self.dispatch_work(key, Work::Event(event)).await;
+    }
+
+    async fn dispatch_work(self: &Arc<Self>, key: String, mut job: Work) {
+        // no new work while the queues are being drained
+        if self.shutting_down.load(AtomicOrdering::SeqCst) {
+            return;
+        }
+        let mut workers = self.workers.lock().await;
+        loop {
+            let sender = match workers.get(&key) {
+                Some(sender) => sender.clone(),
+                None => {
+                    workers.retain(|_, sender| !sender.is_closed());
+                    let sender = self.clone().spawn_worker(key.clone());
+                    workers.insert(key.clone(), sender.clone());
+                    sender
+                }
+            };
+            match sender
+                .send_timeout(job, Duration::from_millis(HANDLER_QUEUE_WAIT))
+                .await
+            {
+                Ok(()) => return,
+                Err(SendTimeoutError::Timeout(job)) => {
+                    metrics().inc("sjmb_queue_dropped_total", &[("queue", "handler")]);
+                    error!("Handler queue for {key:?} is full, dropping {job:?}");
+                    return;
+                }
+                Err(SendTimeoutError::Closed(j)) => {
+                    // the worker went idle, start a new one
+                    workers.remove(&key);
+                    job = j;
+                }
+            }
+        }
+    }
 
-                    if channel == my_nick {
-                        if let Err(e) = self.clone().handle_privmsg(msg, cmd, args).await {
-                            error!("PRIVMSG handling failed: {e}");
+    fn spawn_worker(self: Arc<Self>, key: String) -> mpsc::Sender<Work> {
+        let (sender, mut rx) = mpsc::channel::<Work>(HANDLER_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            debug!("Starting handler worker for {key:?}");
+            loop {
+                match tokio::time::timeout(Duration::from_millis(HANDLER_WORKER_IDLE), rx.recv()).await {
+                    Ok(Some(job)) => self.clone().handle_work(job).await,
+                    Ok(None) => break,
+                    Err(_) => {
+                        // idle, but finish whatever was queued before we closed
+                        rx.close();
+                        while let Ok(job) = rx.try_recv() {
+                            self.clone().handle_work(job).await;
                         }
-                    } else if let Err(e) = self.clone().handle_chanmsg(channel, msg, cmd, args).await {
-                        error!("CHANMSG handling failed: {e}");
+                        break;
                     }
                 }
+            }
+            debug!("Handler worker for {key:?} stopped");
+        });
+        sender
+    }
 
-                Command::NICK(new_nick) => {
-                    debug!("NICK: {msg_nick} USER: {msg_user} HOST: {msg_host} NEW NICK: {new_nick}");
-                    if msg_nick == *my_nick {
-                        info!("My NEW nick: {new_nick}");
-                        self.state.write().await.my_nick = new_nick;
+    async fn handle_work(self: Arc<Self>, job: Work) {
+        match job {
+            Work::Message(ctx, cmd) => self.handle_message(*ctx, cmd).await,
+            Work::Event(event) => self.plugins_event(event).await,
+        }
+    }
+
+    async fn handle_message(self: Arc<Self>, ctx: MsgContext, cmd: Command) {
+        let Ok(_permit) = self.handler_permits.clone().acquire_owned().await else {
+            return;
+        };
+
+        let handlers_irc_cmd = self.handlers.read().await.handlers_irc_cmd.clone();
+        for c in handlers_irc_cmd {
+            if let Ok(true) = handler_timeout("IRC command handler", c(self.clone(), ctx.clone(), cmd.clone())).await {
+                break;
+            }
+        }
+
+        match cmd {
+            Command::JOIN(..) => {
+                self.clone().plugins_join(ctx).await;
+            }
+
+            Command::PRIVMSG(..) => {
+                if ctx.channel.is_none() {
+                    if let Err(e) = self.clone().handle_privmsg(ctx).await {
+                        error!("PRIVMSG handling failed: {e}");
                     }
+                } else if let Err(e) = self.clone().handle_chanmsg(ctx).await {
+                    error!("CHANMSG handling failed: {e}");
                 }
+            }
 
-                cmd => {
-                    debug!("Unhandled command: {cmd:?}")
-                }
+            cmd => {
+                trace!("Unhandled command: {cmd:?}")
             }
         }
+    }
 
-        Ok(())
+    // The channel log events of a message, worked out before the channel state forgets who
+    // was where
+    async fn irc_events(&self, cmd: &Command, ctx: &MsgContext, my_nick: &str) -> Vec<LogEvent> {
+        if ctx.nick == "NONE" {
+            // from the server
+            return Vec::new();
+        }
+        let event = |target: &str, kind: LogKind, text: &str| LogEvent {
+            ts: Utc::now(),
+            target: target.to_string(),
+            kind,
+            nick: ctx.nick.clone(),
+            userhost: ctx.userhost.clone(),
+            account: ctx.account.clone(),
+            tags: ctx.tags.clone(),
+            text: text.to_string(),
+        };
+        match cmd {
+            Command::PRIVMSG(target, text) | Command::NOTICE(target, text) => {
+                // a query is logged under the other party
+                let target = match target.eq_ignore_ascii_case(my_nick) {
+                    true => &ctx.nick,
+                    false => target,
+                };
+                let (kind, text) = match text.strip_prefix("\x01ACTION ") {
+                    Some(action) => (LogKind::Action, action.trim_end_matches('\x01')),
+                    // other CTCP is not worth logging
+                    None if text.starts_with('\x01') => return Vec::new(),
+                    None if matches!(cmd, Command::NOTICE(..)) => (LogKind::Notice, text.as_str()),
+                    None => (LogKind::Message, text.as_str()),
+                };
+                vec![event(target, kind, text)]
+            }
+            Command::JOIN(channel, ..) => vec![event(channel, LogKind::Join, "")],
+            Command::PART(channel, reason) => {
+                vec![event(channel, LogKind::Part, reason.as_deref().unwrap_or_default())]
+            }
+            Command::KICK(channel, nick, reason) => vec![event(
+                channel,
+                LogKind::Kick(nick.clone()),
+                reason.as_deref().unwrap_or_default(),
+            )],
+            Command::QUIT(reason) => self
+                .channel_modes
+                .read()
+                .await
+                .channels_of(&ctx.nick)
+                .iter()
+                .map(|channel| event(channel, LogKind::Quit, reason.as_deref().unwrap_or_default()))
+                .collect(),
+            Command::NICK(new_nick) => self
+                .channel_modes
+                .read()
+                .await
+                .channels_of(&ctx.nick)
+                .iter()
+                .map(|channel| event(channel, LogKind::Nick(new_nick.clone()), ""))
+                .collect(),
+            Command::ChannelMODE(channel, modes) => {
+                let modes = modes.iter().map(ToString::to_string).collect::<Vec<_>>();
+                vec![event(channel, LogKind::Mode, &modes.join(" "))]
+            }
+            Command::TOPIC(channel, Some(topic)) => vec![event(channel, LogKind::Topic, topic)],
+            _ => Vec::new(),
+        }
     }
 
     async fn track_channel_modes(&self, cmd: &Command, msg_nick: &str, my_nick: &str) {
@@ -609,264 +1301,205 @@ impl IrcBot {
 
     pub async fn new_op(self: Arc<Self>, op: IrcOp) -> anyhow::Result<bool> {
         debug!("new_op({op:?})");
-        let sender = self.state.read().await.op_sender.clone();
-        queue_send(&sender, op, "op").await?;
+        self.outbound.op(op).await?;
         debug!("new_op sent to queue");
         Ok(true)
     }
 
+    pub async fn new_url_job(self: Arc<Self>, job: UrlJob) -> anyhow::Result<bool> {
+        debug!("new_url_job({job:?})");
+        self.url_workers.submit(job).await?;
+        Ok(true)
+    }
+
     pub async fn new_msg(self: Arc<Self>, target: &str, msg: &str) -> anyhow::Result<bool> {
-        let (target_s, msg_s) = (target.to_string(), msg.to_string());
-        let (my_nick, sender) = {
-            let state = self.state.read().await;
-            (state.my_nick.clone(), state.msg_sender.clone())
-        };
-        info!("{target_s} <{my_nick}> {msg_s}");
-        queue_send(
-            &sender,
-            IrcMsg {
-                target: target_s,
-                msg: msg_s,
-            },
-            "msg",
-        )
-        .await?;
+        self.outbound.privmsg(target, msg).await?;
         Ok(true)
     }
 
-    // Process private messages here and return true only if something was reacted upon
-    async fn handle_privmsg(self: Arc<Self>, msg: String, cmd: String, args: String) -> anyhow::Result<bool> {
-        let nick = self.state.read().await.msg_nick.clone();
-        let userhost = &self.state.read().await.msg_userhost.clone();
-        info!("*** Privmsg from {nick} ({userhost}): {cmd} {args}");
+    pub async fn new_notice(self: Arc<Self>, target: &str, msg: &str) -> anyhow::Result<bool> {
+        self.outbound.notice(target, msg).await?;
+        Ok(true)
+    }
 
-        let nick_is_privileged = matches!(self.config.read().await.privileged_nicks.get(&nick), Some(true));
+    // Process private messages here and return true only if something was reacted upon
+    async fn handle_privmsg(self: Arc<Self>, ctx: MsgContext) -> anyhow::Result<bool> {
+        info!(
+            "*** Privmsg from {} ({}): {} {}",
+            ctx.nick, ctx.userhost, ctx.cmd, ctx.args
+        );
 
-        if nick_is_privileged
+        if self.is_privileged(&ctx.nick).await
             // Handle privileged commands
-            && self.clone().handle_privmsg_priv(msg.clone(), cmd.clone(), args.clone()).await?
+            && self.clone().handle_privmsg_priv(ctx.clone()).await?
         {
             // a command was found and executed if true was returned
             return Ok(true);
         }
 
         // Handle public commands
-        if self.handle_privmsg_open(msg, cmd, args).await? {
+        if self.clone().handle_privmsg_open(ctx.clone()).await? {
             // a command was found and executed if true was returned
             return Ok(true);
         }
 
-        // All other private messages are ignored
-        Ok(false)
+        // All other private messages go to the plugins
+        Ok(self.plugins_message(ctx).await)
     }
 
     // Process privileged commands here and return true only if something was reacted upon
-    async fn handle_privmsg_priv(self: Arc<Self>, msg: String, cmd: String, args: String) -> anyhow::Result<bool> {
-        match self.handlers.read().await.handlers_privmsg_priv.get(&cmd) {
-            Some(handler) => handler(self.clone(), msg, cmd, args).await,
+    async fn handle_privmsg_priv(self: Arc<Self>, ctx: MsgContext) -> anyhow::Result<bool> {
+        let handler = self.handlers.read().await.handlers_privmsg_priv.get(&ctx.cmd).cloned();
+        match handler {
+            Some(handler) => {
+                metrics().inc("sjmb_commands_total", &[("command", &ctx.cmd)]);
+                handler_timeout(&ctx.cmd, handler(self.clone(), ctx.clone())).await
+            }
             _ => Ok(false), // did not recognize any command
         }
     }
 
     // Process "public" commands here and return true only if something was reacted upon
-    async fn handle_privmsg_open(self: Arc<Self>, msg: String, cmd: String, args: String) -> anyhow::Result<bool> {
-        match self.handlers.read().await.handlers_privmsg_open.get(&cmd) {
-            Some(handler) => handler(self.clone(), msg, cmd, args).await,
+    async fn handle_privmsg_open(self: Arc<Self>, ctx: MsgContext) -> anyhow::Result<bool> {
+        let handler = self.handlers.read().await.handlers_privmsg_open.get(&ctx.cmd).cloned();
+        match handler {
+            Some(handler) => {
+                metrics().inc("sjmb_commands_total", &[("command", &ctx.cmd)]);
+                handler_timeout(&ctx.cmd, handler(self.clone(), ctx.clone())).await
+            }
             _ => Ok(false), // did not recognize any command
         }
     }
 
     // Process channel messages here and return true only if something was reacted upon
-    async fn handle_chanmsg(
-        self: Arc<Self>,
-        channel: String,
-        msg: String,
-        cmd: String,
-        args: String,
-    ) -> anyhow::Result<bool> {
-        let nick = self.state.read().await.msg_nick.clone();
-        debug!("{channel} <{nick}> {cmd} {args}");
-
-        if let Some(handler) = self.handlers.read().await.handlers_chanmsg.get(&cmd) {
-            return handler(self.clone(), msg, cmd, args).await;
+    async fn handle_chanmsg(self: Arc<Self>, mut ctx: MsgContext) -> anyhow::Result<bool> {
+        let channel = ctx.reply_target().to_string();
+        debug!("{channel} <{}> {} {}", ctx.nick, ctx.cmd, ctx.args);
+
+        let handler = self.handlers.read().await.handlers_chanmsg.get(&ctx.cmd).cloned();
+        if let Some(handler) = handler {
+            metrics().inc("sjmb_commands_total", &[("command", &ctx.cmd)]);
+            return handler_timeout(&ctx.cmd, handler(self.clone(), ctx.clone())).await;
         }
 
-        let (handled, queued_actions) = {
+        {
             let cfg = self.config.read().await;
-            let mut queued_actions = Vec::new();
-
-            // url_cmd starts with '!'
-            if let (Some(u_cmd), Some(true)) = (cmd.strip_prefix('!'), get_wild(&cfg.url_cmd_channels, &channel))
-                && let Some(c) = cfg.url_cmd_list.get(u_cmd)
+            'outer: for url_cap in cfg
+                .url_re
+                .as_ref()
+                .ok_or_else(|| anyhow!("No url_regex_re"))?
+                .captures_iter(ctx.msg.as_ref())
             {
-                let u_args = args.split_whitespace().collect::<Vec<&str>>();
-                debug!("Url cmd ctx arg: {args:?}");
-                debug!("Url cmd ctx args: {u_args:?}");
-
-                let mut ctx = tera::Context::new();
-                ctx.insert("arg", &args);
-                ctx.insert("args", &u_args);
-                debug!("Url cmd ctx: {ctx:#?}");
-
-                let url = cfg
-                    .url_cmd_tera
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("No tera"))?
-                    .render(u_cmd, &ctx)?;
-                info!("URL cmd: !{u_cmd} --> {url}");
-                let f = c
-                    .output_filter_re
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("No output regex"))?
-                    .clone();
-                queued_actions.push(QueuedAction::Op(IrcOp::UrlFetch(url, channel.to_string(), f)));
-                (true, queued_actions)
-            } else {
-                let mut found_url = false;
-                'outer: for url_cap in cfg
-                    .url_re
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("No url_regex_re"))?
-                    .captures_iter(msg.as_ref())
-                {
-                    found_url = true;
-                    let url_s = url_cap[1].to_string();
-                    info!("*** ({nick} at {channel}) detected url: {url_s}");
-
-                    for b in &cfg.url_blacklist {
-                        if url_s.starts_with(b) {
-                            info!("*** Blacklilsted URL. Ignored.");
-                            continue 'outer;
-                        }
-                    }
-
-                    if let Some(true) = get_wild(&cfg.url_log_channels, &channel) {
-                        let db = cfg
-                            .db
-                            .clone()
-                            .ok_or_else(|| anyhow!("No database pool for URL logging"))?;
-
-                        if let Some(true) = get_wild(&cfg.url_dup_complain_channels, &channel) {
-                            let expire_days = get_wild(&cfg.url_dup_expire_days, &channel).unwrap_or(&7);
-                            let tz = get_wild(cfg.url_dup_tz.as_ref().unwrap(), &channel).unwrap_or(&Tz::UTC);
-                            queued_actions.push(QueuedAction::Op(IrcOp::UrlCheck(
-                                db.clone(),
-                                url_s.clone(),
-                                channel.to_owned(),
-                                tz.to_owned(),
-                                expire_days.to_owned(),
-                            )));
-                        }
+                let url_s = url_cap[1].to_string();
+                info!("*** ({} at {channel}) detected url: {url_s}", ctx.nick);
 
-                        let op = IrcOp::UrlLog(
-                            db,
-                            url_s.clone(),
-                            channel.to_owned(),
-                            nick.to_owned(),
-                            Utc::now().timestamp(),
-                        );
-                        debug!("New op: {op:?}");
-                        queued_actions.push(QueuedAction::Op(op));
-                    }
-
-                    if let Some(true) = get_wild(&cfg.url_fetch_channels, &channel) {
-                        queued_actions.push(QueuedAction::Op(IrcOp::UrlTitle(url_s.clone(), channel.to_owned())));
-                    }
-
-                    if let Some(true) = get_wild(&cfg.url_mut_channels, &channel)
-                        && let Some((_i, new_url)) = cfg
-                            .url_mut_re
-                            .as_ref()
-                            .ok_or_else(|| anyhow!("No url_mut_re"))?
-                            .re_mut(&url_s)
-                    {
-                        debug!("Doing url mut");
-                        queued_actions.push(QueuedAction::Msg(IrcMsg {
-                            target: channel.clone(),
-                            msg: new_url.clone(),
-                        }));
-                        queued_actions.push(QueuedAction::Op(IrcOp::UrlTitle(new_url, channel.to_string())));
+                for b in &cfg.url_blacklist {
+                    if url_s.starts_with(b) {
+                        info!("*** Blacklilsted URL. Ignored.");
+                        continue 'outer;
                     }
                 }
-
-                (found_url, queued_actions)
+                ctx.urls.push(url_s);
             }
-        };
+        }
 
-        for queued_action in queued_actions {
-            match queued_action {
-                QueuedAction::Msg(queued_msg) => {
-                    self.clone().new_msg(&queued_msg.target, &queued_msg.msg).await?;
-                }
-                QueuedAction::Op(queued_op) => {
-                    self.clone().new_op(queued_op).await?;
-                }
+        Ok(self.plugins_message(ctx).await)
+    }
+
+    // A failing plugin does not keep the later ones from seeing the message
+    async fn plugins_message(self: Arc<Self>, ctx: MsgContext) -> bool {
+        let plugins = self.plugins.read().await.clone();
+        for plugin in plugins {
+            match handler_timeout(plugin.name(), plugin.on_message(self.clone(), ctx.clone())).await {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => error!("Plugin {} on_message failed: {e}", plugin.name()),
             }
         }
+        false
+    }
 
-        // more processing might happen here
-        if handled {
-            return Ok(true);
+    async fn plugins_join(self: Arc<Self>, ctx: MsgContext) {
+        let plugins = self.plugins.read().await.clone();
+        for plugin in plugins {
+            match handler_timeout(plugin.name(), plugin.on_join(self.clone(), ctx.clone())).await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => error!("Plugin {} on_join failed: {e}", plugin.name()),
+            }
         }
-        // ...or here
-
-        Ok(false)
     }
-}
 
-// We are throttling messages here
-async fn read_msg_queue(irc_sender: Arc<Sender>, mut rx: mpsc::Receiver<IrcMsg>) {
-    while let Some(m) = rx.recv().await {
-        debug!("read_msg_queue: new msg: {m:?}");
-        let (target, msg) = (m.target, m.msg);
-        if let Err(e) = irc_sender.send_privmsg(target, msg) {
-            error!("{e}");
+    async fn plugins_event(self: Arc<Self>, event: LogEvent) {
+        let plugins = self.plugins.read().await.clone();
+        for plugin in plugins {
+            if let Err(e) = handler_timeout(plugin.name(), plugin.on_event(self.clone(), event.clone())).await {
+                error!("Plugin {} on_event failed: {e}", plugin.name());
+            }
         }
-        sleep(Duration::from_millis(IRC_MSG_THROTTLE)).await;
     }
-}
-
-// We are throttling operations (mode/join/invite/nick etc) here
-async fn read_op_queue(
-    irc_sender: Arc<Sender>,
-    channel_modes: Arc<RwLock<ChannelModes>>,
-    mut rx: mpsc::Receiver<IrcOp>,
-) {
-    while let Some(op) = rx.recv().await {
-        debug!("read_op_queue: new op: {op:?}");
 
-        if let IrcOp::ModeOper(channel, nick) = &op
-            && channel_modes.read().await.has_oper(channel, nick)
-        {
-            info!("Skipping duplicate +o for {nick} on {channel}");
-            sleep_op_throttle().await;
-            continue;
+    async fn plugins_connect(self: Arc<Self>) {
+        let plugins = self.plugins.read().await.clone();
+        for plugin in plugins {
+            if let Err(e) = handler_timeout(plugin.name(), plugin.on_connect(self.clone())).await {
+                error!("Plugin {} on_connect failed: {e}", plugin.name());
+            }
         }
+    }
 
-        let mark_oper = match &op {
-            IrcOp::ModeOper(channel, nick) => Some((channel.clone(), nick.clone())),
-            _ => None,
-        };
+    async fn plugins_tick(self: Arc<Self>) {
+        let plugins = self.plugins.read().await.clone();
+        for plugin in plugins {
+            if let Err(e) = handler_timeout(plugin.name(), plugin.on_tick(self.clone())).await {
+                error!("Plugin {} on_tick failed: {e}", plugin.name());
+            }
+        }
+    }
 
-        match op_dispatch(irc_sender.clone(), op).await {
-            Ok(()) => {
-                if let Some((channel, nick)) = mark_oper {
-                    channel_modes.write().await.mark_oper(&channel, &nick);
-                }
+    async fn plugins_reload(self: Arc<Self>) {
+        let plugins = self.plugins.read().await.clone();
+        for plugin in plugins {
+            if let Err(e) = plugin.on_reload(self.clone()).await {
+                error!("Plugin {} on_reload failed: {e}", plugin.name());
             }
-            Err(e) => error!("{e}"),
         }
+    }
+}
 
-        sleep_op_throttle().await;
+async fn handler_timeout<T>(name: &str, f: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    match tokio::time::timeout(Duration::from_millis(HANDLER_TIMEOUT), f).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow!("handler {name} timed out after {HANDLER_TIMEOUT} ms")),
     }
 }
 
-async fn sleep_op_throttle() {
-    let throttle_ms = IRC_OP_THROTTLE + rand::random_range(0..=IRC_OP_THROTTLE_JITTER);
-    sleep(Duration::from_millis(throttle_ms)).await;
+// Sends an op unless it is redundant, returns false if it was skipped
+pub(crate) async fn op_send(
+    irc_sender: &Sender,
+    channel_modes: &RwLock<ChannelModes>,
+    op: IrcOp,
+) -> anyhow::Result<bool> {
+    if let IrcOp::ModeOper(channel, nick) = &op
+        && channel_modes.read().await.has_oper(channel, nick)
+    {
+        info!("Skipping duplicate +o for {nick} on {channel}");
+        return Ok(false);
+    }
+
+    let mark_oper = match &op {
+        IrcOp::ModeOper(channel, nick) => Some((channel.clone(), nick.clone())),
+        _ => None,
+    };
+
+    op_dispatch(irc_sender, op)?;
+    if let Some((channel, nick)) = mark_oper {
+        channel_modes.write().await.mark_oper(&channel, &nick);
+    }
+    Ok(true)
 }
 
-async fn queue_send<T>(sender: &mpsc::Sender<T>, mut item: T, kind: &str) -> anyhow::Result<()>
+pub(crate) async fn queue_send<T>(sender: &mpsc::Sender<T>, mut item: T, kind: &str) -> anyhow::Result<()>
 where
     T: std::fmt::Debug,
 {
@@ -877,6 +1510,7 @@ where
         {
             Ok(()) => return Ok(()),
             Err(mpsc::error::SendTimeoutError::Timeout(queued_item)) => {
+                metrics().inc("sjmb_queue_full_total", &[("queue", kind)]);
                 error!(
                     "{kind} queue full for {} ms, retrying send: {queued_item:?}",
                     IRC_QUEUE_SEND_TIMEOUT
@@ -892,8 +1526,7 @@ where
     }
 }
 
-#[allow(unused_variables)]
-async fn op_dispatch(irc_sender: Arc<Sender>, op: IrcOp) -> anyhow::Result<()> {
+fn op_dispatch(irc_sender: &Sender, op: IrcOp) -> anyhow::Result<()> {
     match op {
         IrcOp::Invite(nick, channel) => irc_sender.send_invite(nick, channel)?,
         IrcOp::Join(newchan) => irc_sender.send(Command::JOIN(newchan, None, None))?,
@@ -904,108 +1537,7 @@ async fn op_dispatch(irc_sender: Arc<Sender>, op: IrcOp) -> anyhow::Result<()> {
             irc_sender.send_mode(channel, &[Mode::Plus(ChannelMode::Voice, Some(nick))])?
         }
         IrcOp::Nick(newnick) => irc_sender.send(Command::NICK(newnick))?,
-        IrcOp::UrlCheck(db, url, channel, tz, days) => {
-            op_handle_urlcheck(irc_sender.clone(), db, url, channel, tz, days).await?
-        }
-        IrcOp::UrlFetch(url, channel, output_filter) => {
-            op_handle_urlfetch(irc_sender.clone(), url, channel, output_filter).await?
-        }
-        IrcOp::UrlLog(db, url, channel, nick, ts) => op_handle_urllog(db, url, channel, nick, ts).await?,
-        IrcOp::UrlTitle(url, channel) => op_handle_urltitle(irc_sender.clone(), url, channel).await?,
-    }
-    Ok(())
-}
-
-async fn op_handle_urlcheck(
-    irc_sender: Arc<Sender>,
-    db: DbCtx,
-    url: String,
-    channel: String,
-    tz: Tz,
-    exp_days: i64,
-) -> anyhow::Result<()> {
-    debug!("op_handle_urlcheck(): url {url}");
-    if let Some(old) = db_check_url(&db, &url, &channel, exp_days * 86400).await?
-        && let (Some(first), Some(last)) = (old.first, old.last)
-    {
-        let ts_first = DateTime::from_timestamp(first, 0)
-            .unwrap_or_default()
-            .with_timezone(&tz);
-
-        match old.cnt.cmp(&1) {
-            Ordering::Equal => {
-                irc_sender.send_privmsg(channel, format!("Wanha URL, nähty {ts_first}"))?;
-            }
-            Ordering::Greater => {
-                let ts_last = DateTime::from_timestamp(last, 0).unwrap_or_default().with_timezone(&tz);
-                irc_sender.send_privmsg(
-                    channel,
-                    format!(
-                        "Wanha URL, nähty {} kertaa, ensin {ts_first} ja viimeksi {ts_last}",
-                        old.cnt
-                    ),
-                )?;
-            }
-            _ => {}
-        }
-    }
-
-    Ok(())
-}
-
-async fn op_handle_urlfetch(
-    irc_sender: Arc<Sender>,
-    url: String,
-    channel: String,
-    output_filter: Regex,
-) -> anyhow::Result<()> {
-    debug!("op_handle_urlfetch()");
-    if let Some((body, _ct)) = get_text_body(&url).await? {
-        for res_cap in output_filter.captures_iter(&body) {
-            let res_str = &res_cap[1];
-            let say = format!("--> {res_str}");
-            irc_sender.send_privmsg(&channel, say)?;
-        }
-    }
-    Ok(())
-}
-
-async fn op_handle_urllog(db: DbCtx, url: String, chan: String, nick: String, ts: i64) -> anyhow::Result<()> {
-    debug!("op_handle_urllog(): insert url {url}");
-    info!(
-        "Urllog: inserted {} row(s)",
-        db_add_url(&db, &UrlCtx { ts, chan, nick, url },).await?
-    );
-    Ok(())
-}
-
-async fn op_handle_urltitle(irc_sender: Arc<Sender>, url: String, channel: String) -> anyhow::Result<()> {
-    debug!("op_handle_urltitle(): fetching url {url}");
-    if let Some((body, _ct)) = get_text_body(&url).await? {
-        debug!("Parsing title from body: url {url}");
-        let html = webpage::HTML::from_string(body, None)?;
-
-        if let Some(title) = html.title
-            // ignore titles that are just the url repeated
-            && title != url
-        {
-            // Replace all consecutive whitespace, including newlines etc with a single space
-            let mut title_c = title.ws_collapse();
-            if title_c.len() > 400 {
-                let mut i = 396;
-                loop {
-                    // find a UTF-8 code point boundary to safely split at
-                    if title_c.is_char_boundary(i) {
-                        break;
-                    }
-                    i += 1;
-                }
-                let (s1, _) = title_c.split_at(i);
-                title_c = format!("{s1}...");
-            }
-            let say = format!("\"{title_c}\"");
-            irc_sender.send_privmsg(channel, say)?;
-        }
+        IrcOp::Part(channel) => irc_sender.send_part(channel)?,
     }
     Ok(())
 }
@@ -1027,6 +1559,41 @@ mod tests {
         assert_eq!(welcome_nickname(&message.command), None);
     }
 
+    #[test]
+    fn msg_context_is_built_from_each_message() {
+        let message: Message = "@account=alice_acct :alice!ali@example.com PRIVMSG #test :!metar efhk now"
+            .parse()
+            .expect("privmsg should parse");
+        let ctx = MsgContext::new(&message, "sjmbot");
+        assert_eq!(ctx.nick, "alice");
+        assert_eq!(ctx.userhost, "ali@example.com");
+        assert_eq!(ctx.account.as_deref(), Some("alice_acct"));
+        assert_eq!(ctx.channel.as_deref(), Some("#test"));
+        assert_eq!(ctx.reply_target(), "#test");
+        assert_eq!((ctx.cmd.as_str(), ctx.args.as_str()), ("!metar", "efhk now"));
+
+        let message: Message = ":bob!b@example.org PRIVMSG sjmbot :invite"
+            .parse()
+            .expect("privmsg should parse");
+        let ctx = MsgContext::new(&message, "sjmbot");
+        assert_eq!(ctx.channel, None);
+        assert_eq!(ctx.reply_target(), "bob");
+        assert_eq!((ctx.cmd.as_str(), ctx.args.as_str()), ("invite", ""));
+
+        let message: Message = ":bob!b@example.org PRIVMSG SJMBot :invite"
+            .parse()
+            .expect("privmsg should parse");
+        let ctx = MsgContext::new(&message, "sjmbot");
+        assert_eq!(ctx.channel, None);
+
+        let message: Message = ":bob!b@example.org JOIN #test bob_acct :Bob"
+            .parse()
+            .expect("extended join should parse");
+        let ctx = MsgContext::new(&message, "sjmbot");
+        assert_eq!(ctx.account.as_deref(), Some("bob_acct"));
+        assert_eq!(ctx.channel.as_deref(), Some("#test"));
+    }
+
     #[test]
     fn namreply_tracks_operator_prefix() {
         let mut modes = ChannelModes::default();
@@ -0,0 +1,630 @@
+// irclog.rs
+
+// Channel and query logs under irc_log_dir, one file per target and day:
+// <irc_log_dir>/<target>/<YYYY-MM-DD>.log (.jsonl for JSON Lines). The day changes at
+// midnight in the channel's timezone from url_dup_timezone. A single writer thread does
+// the disk I/O, so the run loop and the outbound scheduler only ever queue events.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+    },
+    time::Instant,
+};
+
+use chrono_tz::Tz;
+use flate2::{Compression, write::GzEncoder};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+
+use crate::*;
+
+const IRC_LOG_QUEUE_CAPACITY: usize = 1024;
+// in milliseconds, files not written to for this long are closed
+const IRC_LOG_FILE_IDLE: u64 = 600_000;
+// in milliseconds, how often the writer closes idle and past-day files without any events
+const IRC_LOG_TICK: u64 = 60_000;
+// in milliseconds, how often the directory is swept for uncompressed files of past days
+const IRC_LOG_SWEEP_INTERVAL: u64 = 3_600_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // our own compact layout
+    #[default]
+    Text,
+    // what irssi writes with its default theme, for pisg and friends
+    Irssi,
+    // one JSON object per line
+    Jsonl,
+}
+
+impl LogFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            LogFormat::Text | LogFormat::Irssi => "log",
+            LogFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogKind {
+    Message,
+    Action,
+    Notice,
+    Join,
+    Part,
+    Quit,
+    // the nick that was kicked
+    Kick(String),
+    // the new nick
+    Nick(String),
+    Mode,
+    Topic,
+}
+
+impl LogKind {
+    fn command(&self) -> &'static str {
+        match self {
+            LogKind::Message => "PRIVMSG",
+            LogKind::Action => "ACTION",
+            LogKind::Notice => "NOTICE",
+            LogKind::Join => "JOIN",
+            LogKind::Part => "PART",
+            LogKind::Quit => "QUIT",
+            LogKind::Kick(_) => "KICK",
+            LogKind::Nick(_) => "NICK",
+            LogKind::Mode => "MODE",
+            LogKind::Topic => "TOPIC",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogEvent {
+    pub ts: DateTime<Utc>,
+    // the channel, or the other party of a query
+    pub target: String,
+    pub kind: LogKind,
+    pub nick: String,
+    pub userhost: String,
+    pub account: Option<String>,
+    pub tags: Vec<Tag>,
+    // message, reason, modes or topic
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IrcLogConfig {
+    // empty disables logging
+    pub dir: String,
+    pub channels: HashMap<String, bool>,
+    pub formats: HashMap<String, LogFormat>,
+    pub timezones: HashMap<String, Tz>,
+    // compress the files of past days
+    pub gzip: bool,
+}
+
+impl IrcLogConfig {
+    pub fn new(cfg: &BotConfig) -> Self {
+        Self {
+            dir: cfg.irc_log_dir.clone(),
+            channels: cfg.irc_log_channels.clone(),
+            formats: cfg.irc_log_format.clone(),
+            timezones: cfg.url_dup_tz.clone().unwrap_or_default(),
+            gzip: cfg.irc_log_gzip,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IrcLogger {
+    sender: mpsc::Sender<LogEvent>,
+    cfg: Arc<Mutex<IrcLogConfig>>,
+}
+
+impl IrcLogger {
+    pub fn start(cfg: IrcLogConfig) -> Self {
+        let (sender, rx) = mpsc::channel(IRC_LOG_QUEUE_CAPACITY);
+        let cfg = Arc::new(Mutex::new(cfg));
+        let writer_cfg = cfg.clone();
+        tokio::task::spawn_blocking(move || run_writer(rx, writer_cfg));
+        Self { sender, cfg }
+    }
+
+    pub fn set_config(&self, cfg: IrcLogConfig) {
+        *lock_cfg(&self.cfg) = cfg;
+    }
+
+    pub fn enabled(&self, target: &str) -> bool {
+        let cfg = lock_cfg(&self.cfg);
+        !cfg.dir.is_empty() && matches!(get_wild(&cfg.channels, target), Some(true))
+    }
+
+    // Never waits, a full queue drops the event
+    pub fn log(&self, event: LogEvent) {
+        if !self.enabled(&event.target) {
+            return;
+        }
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => error!("IRC log queue full, dropped {event:?}"),
+            Err(TrySendError::Closed(_)) => error!("IRC log writer is gone"),
+        }
+    }
+}
+
+fn lock_cfg(cfg: &Mutex<IrcLogConfig>) -> MutexGuard<'_, IrcLogConfig> {
+    cfg.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct OpenLog {
+    path: PathBuf,
+    file: File,
+    format: LogFormat,
+    day: NaiveDate,
+    tz: Tz,
+    last_write: Instant,
+}
+
+fn run_writer(mut rx: mpsc::Receiver<LogEvent>, cfg: Arc<Mutex<IrcLogConfig>>) {
+    debug!("Starting IRC log writer");
+    // we are on a blocking thread of the runtime, see IrcLogger::start()
+    let rt = tokio::runtime::Handle::current();
+    let mut files = HashMap::new();
+    let sweeping = Arc::new(AtomicBool::new(false));
+    let mut last_sweep: Option<Instant> = None;
+    let mut sweep_due = true;
+    loop {
+        // the timeout keeps the idle closing and rotation going on quiet channels
+        let event = match rx.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {
+                match rt.block_on(tokio::time::timeout(Duration::from_millis(IRC_LOG_TICK), rx.recv())) {
+                    Ok(Some(event)) => Some(event),
+                    Ok(None) => break,
+                    Err(_) => None,
+                }
+            }
+        };
+
+        let (dir, gzip) = {
+            let cfg = lock_cfg(&cfg);
+            (PathBuf::from(&cfg.dir), cfg.gzip)
+        };
+        if let Some(event) = event {
+            let (tz, format) = {
+                let cfg = lock_cfg(&cfg);
+                (
+                    get_wild(&cfg.timezones, &event.target).copied().unwrap_or(Tz::UTC),
+                    get_wild(&cfg.formats, &event.target).copied().unwrap_or_default(),
+                )
+            };
+            if let Err(e) = write_event(&mut files, &dir, tz, format, &event) {
+                error!("IRC log for {}: {e}", event.target);
+            }
+        }
+
+        let done = files
+            .iter()
+            .filter(|(_, f)| {
+                f.last_write.elapsed() >= Duration::from_millis(IRC_LOG_FILE_IDLE) || f.day < local_today(f.tz)
+            })
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        for key in done {
+            if let Some(log) = files.remove(&key)
+                && close_log(log)
+            {
+                sweep_due = true;
+            }
+        }
+
+        if gzip
+            && !dir.as_os_str().is_empty()
+            && (sweep_due || last_sweep.is_none_or(|t| t.elapsed() >= Duration::from_millis(IRC_LOG_SWEEP_INTERVAL)))
+            && !sweeping.swap(true, AtomicOrdering::AcqRel)
+        {
+            sweep_due = false;
+            last_sweep = Some(Instant::now());
+            let open = files.values().map(|f| f.path.clone()).collect::<HashSet<_>>();
+            let timezones = lock_cfg(&cfg).timezones.clone();
+            let sweeping = sweeping.clone();
+            std::thread::spawn(move || {
+                sweep_logs(&dir, &timezones, &open);
+                sweeping.store(false, AtomicOrdering::Release);
+            });
+        }
+    }
+}
+
+fn local_today(tz: Tz) -> NaiveDate {
+    Utc::now().with_timezone(&tz).date_naive()
+}
+
+fn write_event(
+    files: &mut HashMap<String, OpenLog>,
+    dir: &Path,
+    tz: Tz,
+    format: LogFormat,
+    event: &LogEvent,
+) -> anyhow::Result<()> {
+    let local = event.ts.with_timezone(&tz);
+    let day = local.date_naive();
+    let path = log_path(dir, &event.target, day, format);
+    let key = event.target.to_lowercase();
+
+    // a new day, or a new irc_log_dir or format after reload
+    if files.get(&key).is_none_or(|f| f.path != path) {
+        if let Some(old) = files.remove(&key) {
+            close_log(old);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        debug!("IRC log opened: {}", path.display());
+        if format == LogFormat::Irssi {
+            writeln!(file, "--- Log opened {}", local.format("%a %b %d %H:%M:%S %Y"))?;
+        }
+        files.insert(
+            key.clone(),
+            OpenLog {
+                path,
+                file,
+                format,
+                day,
+                tz,
+                last_write: Instant::now(),
+            },
+        );
+    }
+    let Some(log) = files.get_mut(&key) else {
+        bail!("log file went missing");
+    };
+    log.file.write_all(format_line(format, event, &local)?.as_bytes())?;
+    log.last_write = Instant::now();
+    Ok(())
+}
+
+// Returns true if the file was of a past day, to be compressed by sweep_logs()
+fn close_log(mut log: OpenLog) -> bool {
+    let now = Utc::now().with_timezone(&log.tz);
+    if log.format == LogFormat::Irssi
+        && let Err(e) = writeln!(log.file, "--- Log closed {}", now.format("%a %b %d %H:%M:%S %Y"))
+    {
+        error!("IRC log {}: {e}", log.path.display());
+    }
+    drop(log.file);
+    debug!("IRC log closed: {}", log.path.display());
+    log.day < now.date_naive()
+}
+
+// Compresses the plain files of the days that are over in their target's timezone, also
+// the ones closed as idle before midnight, except those still open
+fn sweep_logs(dir: &Path, timezones: &HashMap<String, Tz>, open: &HashSet<PathBuf>) {
+    let Ok(targets) = fs::read_dir(dir) else {
+        return;
+    };
+    for target in targets.flatten() {
+        let name = target.file_name().to_string_lossy().into_owned();
+        let tz = timezones
+            .iter()
+            .find(|(k, _)| file_name_safe(k) == name)
+            .map(|(_, tz)| *tz)
+            .or_else(|| timezones.get("*").copied())
+            .unwrap_or(Tz::UTC);
+        let today = local_today(tz);
+        let Ok(files) = fs::read_dir(target.path()) else {
+            continue;
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            let plain = matches!(path.extension().and_then(|e| e.to_str()), Some("log" | "jsonl"));
+            let day = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok());
+            if plain
+                && !open.contains(&path)
+                && day.is_some_and(|d| d < today)
+                && let Err(e) = gzip_file(&path)
+            {
+                error!("Could not compress {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+// Appends to an existing .gz, concatenated gzip members are still a valid gzip file
+fn gzip_file(path: &Path) -> anyhow::Result<()> {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+    let gz = OpenOptions::new().create(true).append(true).open(&gz_name)?;
+    let mut encoder = GzEncoder::new(gz, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)?;
+    info!("IRC log compressed: {}", path.display());
+    Ok(())
+}
+
+fn log_path(dir: &Path, target: &str, day: NaiveDate, format: LogFormat) -> PathBuf {
+    dir.join(file_name_safe(target))
+        .join(format!("{}.{}", day.format("%Y-%m-%d"), format.extension()))
+}
+
+// Channel names may contain almost anything, nicks are less creative
+fn file_name_safe(target: &str) -> String {
+    let name = target
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect::<String>();
+    match name.starts_with('.') {
+        true => format!("_{name}"),
+        false => name,
+    }
+}
+
+fn format_line(format: LogFormat, event: &LogEvent, local: &DateTime<Tz>) -> anyhow::Result<String> {
+    let line = match format {
+        LogFormat::Text => format_text(event, local),
+        LogFormat::Irssi => format_irssi(event, local),
+        LogFormat::Jsonl => format_jsonl(event, local)?,
+    };
+    Ok(format!("{line}\n"))
+}
+
+fn format_text(event: &LogEvent, local: &DateTime<Tz>) -> String {
+    let ts = local.format("%H:%M:%S");
+    let LogEvent {
+        nick,
+        userhost,
+        text,
+        target,
+        ..
+    } = event;
+    let line = match &event.kind {
+        LogKind::Message => format!("<{nick}> {text}"),
+        LogKind::Action => format!("* {nick} {text}"),
+        LogKind::Notice => format!("-{nick}- {text}"),
+        LogKind::Join => format!("-!- {nick} ({userhost}) joined {target}"),
+        LogKind::Part => format!("-!- {nick} ({userhost}) left {target} ({text})"),
+        LogKind::Quit => format!("-!- {nick} ({userhost}) quit ({text})"),
+        LogKind::Kick(victim) => format!("-!- {victim} was kicked from {target} by {nick} ({text})"),
+        LogKind::Nick(new_nick) => format!("-!- {nick} is now known as {new_nick}"),
+        LogKind::Mode => format!("-!- mode {target} [{text}] by {nick}"),
+        LogKind::Topic => format!("-!- {nick} changed the topic of {target} to: {text}"),
+    };
+    format!("{ts} {line}")
+}
+
+// The irssi default theme, which is what pisg and similar tools parse
+fn format_irssi(event: &LogEvent, local: &DateTime<Tz>) -> String {
+    let ts = local.format("%H:%M");
+    let LogEvent {
+        nick,
+        userhost,
+        text,
+        target,
+        ..
+    } = event;
+    let line = match &event.kind {
+        LogKind::Message => format!("< {nick}> {text}"),
+        LogKind::Action => format!(" * {nick} {text}"),
+        LogKind::Notice if is_channel_name(target) => format!("-{nick}:{target}- {text}"),
+        LogKind::Notice => format!("-{nick}({userhost})- {text}"),
+        LogKind::Join => format!("-!- {nick} [{userhost}] has joined {target}"),
+        LogKind::Part => format!("-!- {nick} [{userhost}] has left {target} [{text}]"),
+        LogKind::Quit => format!("-!- {nick} [{userhost}] has quit [{text}]"),
+        LogKind::Kick(victim) => format!("-!- {victim} was kicked from {target} by {nick} [{text}]"),
+        LogKind::Nick(new_nick) => format!("-!- {nick} is now known as {new_nick}"),
+        LogKind::Mode => format!("-!- mode/{target} [{text}] by {nick}"),
+        LogKind::Topic => format!("-!- {nick} changed the topic of {target} to: {text}"),
+    };
+    format!("{ts} {line}")
+}
+
+#[derive(Serialize)]
+struct JsonLogLine<'a> {
+    timestamp: String,
+    nick: &'a str,
+    userhost: &'a str,
+    account: Option<&'a str>,
+    command: &'a str,
+    target: &'a str,
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kicked: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_nick: Option<&'a str>,
+    tags: BTreeMap<&'a str, Option<&'a str>>,
+}
+
+fn format_jsonl(event: &LogEvent, local: &DateTime<Tz>) -> anyhow::Result<String> {
+    let line = JsonLogLine {
+        timestamp: local.to_rfc3339_opts(SecondsFormat::Millis, false),
+        nick: &event.nick,
+        userhost: &event.userhost,
+        account: event.account.as_deref(),
+        command: event.kind.command(),
+        target: &event.target,
+        text: &event.text,
+        kicked: match &event.kind {
+            LogKind::Kick(victim) => Some(victim),
+            _ => None,
+        },
+        new_nick: match &event.kind {
+            LogKind::Nick(new_nick) => Some(new_nick),
+            _ => None,
+        },
+        tags: event.tags.iter().map(|Tag(k, v)| (k.as_str(), v.as_deref())).collect(),
+    };
+    Ok(serde_json::to_string(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+
+    fn event(ts: &str, kind: LogKind, text: &str) -> LogEvent {
+        LogEvent {
+            ts: ts.parse().unwrap(),
+            target: "#Chan/X".to_string(),
+            kind,
+            nick: "alice".to_string(),
+            userhost: "al@example.net".to_string(),
+            account: None,
+            tags: Vec::new(),
+            text: text.to_string(),
+        }
+    }
+
+    fn line(format: LogFormat, ev: &LogEvent, tz: Tz) -> String {
+        format_line(format, ev, &ev.ts.with_timezone(&tz)).unwrap()
+    }
+
+    #[test]
+    fn lines_are_formatted_in_local_time() {
+        let tz: Tz = "Europe/Helsinki".parse().unwrap();
+        let ev = event("2026-01-15T21:59:30Z", LogKind::Message, "hello");
+        assert_eq!(line(LogFormat::Text, &ev, tz), "23:59:30 <alice> hello\n");
+        let ev = event("2026-01-15T21:59:30Z", LogKind::Kick("bob".to_string()), "bye");
+        assert_eq!(
+            line(LogFormat::Text, &ev, Tz::UTC),
+            "21:59:30 -!- bob was kicked from #Chan/X by alice (bye)\n"
+        );
+    }
+
+    #[test]
+    fn irssi_layout() {
+        let tz = Tz::UTC;
+        let ts = "2026-01-15T21:59:30Z";
+        assert_eq!(
+            line(LogFormat::Irssi, &event(ts, LogKind::Message, "hello"), tz),
+            "21:59 < alice> hello\n"
+        );
+        assert_eq!(
+            line(LogFormat::Irssi, &event(ts, LogKind::Action, "waves"), tz),
+            "21:59  * alice waves\n"
+        );
+        assert_eq!(
+            line(LogFormat::Irssi, &event(ts, LogKind::Join, ""), tz),
+            "21:59 -!- alice [al@example.net] has joined #Chan/X\n"
+        );
+        assert_eq!(
+            line(LogFormat::Irssi, &event(ts, LogKind::Mode, "+o bob"), tz),
+            "21:59 -!- mode/#Chan/X [+o bob] by alice\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_has_all_fields() {
+        let mut ev = event("2026-01-15T21:59:30Z", LogKind::Nick("alice2".to_string()), "");
+        ev.account = Some("alice".to_string());
+        ev.tags = vec![Tag("time".to_string(), Some("2026-01-15T21:59:30.000Z".to_string()))];
+        let tz: Tz = "Europe/Helsinki".parse().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line(LogFormat::Jsonl, &ev, tz)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": "2026-01-15T23:59:30.000+02:00",
+                "nick": "alice",
+                "userhost": "al@example.net",
+                "account": "alice",
+                "command": "NICK",
+                "target": "#Chan/X",
+                "text": "",
+                "new_nick": "alice2",
+                "tags": {"time": "2026-01-15T21:59:30.000Z"}
+            })
+        );
+    }
+
+    #[test]
+    fn files_rotate_at_local_midnight() {
+        let dir = env::temp_dir().join(format!("sjmb-irclog-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let tz: Tz = "Europe/Helsinki".parse().unwrap();
+        let mut files = HashMap::new();
+
+        // 23:59 and 00:01 in Helsinki, the same UTC day
+        for ev in [
+            event("2026-01-15T21:59:00Z", LogKind::Message, "before"),
+            event("2026-01-15T22:01:00Z", LogKind::Message, "after"),
+        ] {
+            write_event(&mut files, &dir, tz, LogFormat::Text, &ev).unwrap();
+        }
+
+        let chan_dir = dir.join("#chan_x");
+        assert_eq!(
+            fs::read_to_string(chan_dir.join("2026-01-15.log")).unwrap(),
+            "23:59:00 <alice> before\n"
+        );
+        assert_eq!(
+            fs::read_to_string(chan_dir.join("2026-01-16.log")).unwrap(),
+            "00:01:00 <alice> after\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sweep_compresses_closed_past_days() {
+        let dir = env::temp_dir().join(format!("sjmb-irclog-sweep-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let chan_dir = dir.join("#chan");
+        fs::create_dir_all(&chan_dir).unwrap();
+        let today = local_today(Tz::UTC).format("%Y-%m-%d").to_string();
+        for name in ["2026-01-14.log", "2026-01-15.jsonl", "2026-01-16.log", "notes.txt"] {
+            fs::write(chan_dir.join(name), "x\n").unwrap();
+        }
+        fs::write(chan_dir.join(format!("{today}.log")), "x\n").unwrap();
+        let open = HashSet::from([chan_dir.join("2026-01-16.log")]);
+
+        sweep_logs(&dir, &HashMap::new(), &open);
+
+        assert!(chan_dir.join("2026-01-14.log.gz").exists() && !chan_dir.join("2026-01-14.log").exists());
+        assert!(chan_dir.join("2026-01-15.jsonl.gz").exists());
+        assert!(chan_dir.join("2026-01-16.log").exists());
+        assert!(chan_dir.join(format!("{today}.log")).exists());
+        assert!(chan_dir.join("notes.txt").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn gzip_appends_members() {
+        let dir = env::temp_dir().join(format!("sjmb-irclog-gz-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("2026-01-15.log");
+
+        for text in ["first\n", "second\n"] {
+            fs::write(&path, text).unwrap();
+            gzip_file(&path).unwrap();
+            assert!(!path.exists());
+        }
+
+        let mut text = String::new();
+        MultiGzDecoder::new(File::open(dir.join("2026-01-15.log.gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "first\nsecond\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+// EOF
@@ -0,0 +1,164 @@
+// lag.rs
+
+// Our own PINGs to the server, to measure the round-trip lag and to notice a dead link
+// before the socket does. The run loop gives up on the connection when a PING goes
+// unanswered for `max_lag` ms or nothing at all is heard for `idle_timeout` ms.
+
+use std::time::Instant;
+
+use crate::*;
+
+const LAG_PING_PREFIX: &str = "sjmb-";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LagConfig {
+    // in milliseconds, 0 disables any of these
+    pub ping_interval: u64,
+    pub max_lag: u64,
+    pub idle_timeout: u64,
+}
+
+impl Default for LagConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: 60_000,
+            max_lag: 120_000,
+            idle_timeout: 300_000,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LagAction {
+    Nothing,
+    Ping(String),
+    Reconnect(String),
+}
+
+#[derive(Debug)]
+pub struct LagMonitor {
+    last_rx: Instant,
+    last_ping: Instant,
+    // token and send time of the PING we are waiting for
+    pending: Option<(String, Instant)>,
+    lag: Option<Duration>,
+}
+
+impl Default for LagMonitor {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl LagMonitor {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_rx: now,
+            last_ping: now,
+            pending: None,
+            lag: None,
+        }
+    }
+
+    pub fn on_traffic(&mut self, now: Instant) {
+        self.last_rx = now;
+    }
+
+    pub fn on_pong(&mut self, token: &str, now: Instant) {
+        if let Some((pending, sent)) = &self.pending
+            && pending == token
+        {
+            self.lag = Some(now.duration_since(*sent));
+            self.pending = None;
+        }
+    }
+
+    pub fn tick(&mut self, cfg: &LagConfig, now: Instant) -> LagAction {
+        if let Some((_, sent)) = &self.pending
+            && cfg.max_lag > 0
+            && now.duration_since(*sent) > Duration::from_millis(cfg.max_lag)
+        {
+            return LagAction::Reconnect(format!("no PONG in {} ms", cfg.max_lag));
+        }
+        if cfg.idle_timeout > 0 && now.duration_since(self.last_rx) > Duration::from_millis(cfg.idle_timeout) {
+            return LagAction::Reconnect(format!("nothing received in {} ms", cfg.idle_timeout));
+        }
+        if self.pending.is_none()
+            && cfg.ping_interval > 0
+            && now.duration_since(self.last_ping) >= Duration::from_millis(cfg.ping_interval)
+        {
+            let token = format!("{LAG_PING_PREFIX}{}", Utc::now().timestamp_millis());
+            self.pending = Some((token.clone(), now));
+            self.last_ping = now;
+            return LagAction::Ping(token);
+        }
+        LagAction::Nothing
+    }
+
+    // The last measured lag, or the time the current PING has been waiting if that is longer
+    pub fn lag(&self, now: Instant) -> Option<Duration> {
+        let waiting = self.pending.as_ref().map(|(_, sent)| now.duration_since(*sent));
+        match (self.lag, waiting) {
+            (Some(lag), Some(waiting)) => Some(lag.max(waiting)),
+            (lag, waiting) => lag.or(waiting),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CFG: LagConfig = LagConfig {
+        ping_interval: 1000,
+        max_lag: 5000,
+        idle_timeout: 10_000,
+    };
+
+    #[test]
+    fn ping_pong_measures_lag() {
+        let start = Instant::now();
+        let mut mon = LagMonitor::new(start);
+        assert_eq!(mon.tick(&CFG, start), LagAction::Nothing);
+
+        let t1 = start + Duration::from_millis(1000);
+        let LagAction::Ping(token) = mon.tick(&CFG, t1) else {
+            panic!("expected a ping");
+        };
+        // only one ping in flight
+        assert_eq!(mon.tick(&CFG, t1 + Duration::from_millis(1500)), LagAction::Nothing);
+
+        let t2 = t1 + Duration::from_millis(250);
+        mon.on_pong("someone-elses", t2);
+        assert_eq!(mon.lag(t2), Some(Duration::from_millis(250)));
+        mon.on_pong(&token, t2);
+        assert_eq!(mon.lag(t2 + Duration::from_secs(1)), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn unanswered_ping_or_silence_reconnects() {
+        let start = Instant::now();
+        let mut mon = LagMonitor::new(start);
+        let t1 = start + Duration::from_millis(1000);
+        assert!(matches!(mon.tick(&CFG, t1), LagAction::Ping(_)));
+        mon.on_traffic(t1);
+        assert!(matches!(
+            mon.tick(&CFG, t1 + Duration::from_millis(5001)),
+            LagAction::Reconnect(_)
+        ));
+
+        let cfg = LagConfig {
+            ping_interval: 0,
+            ..CFG
+        };
+        let mut mon = LagMonitor::new(start);
+        assert_eq!(mon.tick(&cfg, start + Duration::from_millis(9000)), LagAction::Nothing);
+        assert!(matches!(
+            mon.tick(&cfg, start + Duration::from_millis(10_001)),
+            LagAction::Reconnect(_)
+        ));
+    }
+}
+
+// EOF
@@ -4,23 +4,45 @@ pub use std::{cmp::Ordering, collections::HashMap, env, fs::File, io::BufReader,
 
 pub use anyhow::{anyhow, bail};
 pub use chrono::*;
-pub use irc::client::prelude::*;
+pub use irc::{client::prelude::*, proto::message::Tag};
 pub use regex::Regex;
 pub use serde::{Deserialize, Serialize};
 pub use tokio::{
-    sync::{mpsc, RwLock},
-    time::{sleep, Duration},
+    sync::{RwLock, mpsc},
+    time::{Duration, sleep},
 };
 pub use tracing::*;
 
+pub use admin::*;
 pub use config::*;
+pub use control::*;
 pub use db_util::*;
 pub use ircbot::*;
+pub use irclog::*;
+pub use lag::*;
+pub use metrics::*;
+pub use outbound::*;
+pub use plugin::*;
+pub use plugins::*;
+pub use reconnect::*;
+pub use systemd::*;
+pub use urljob::*;
 pub use util::*;
 
+pub mod admin;
 pub mod config;
+pub mod control;
 pub mod db_util;
 pub mod ircbot;
+pub mod irclog;
+pub mod lag;
+pub mod metrics;
+pub mod outbound;
+pub mod plugin;
+pub mod plugins;
+pub mod reconnect;
+pub mod systemd;
+pub mod urljob;
 pub mod util;
 
 // EOF
@@ -0,0 +1,274 @@
+// metrics.rs
+
+// Prometheus metrics, served as text over plain HTTP from `metrics.listen` when set.
+//
+// The registry is global so that the low level helpers (queue_send, get_body, db_add_url)
+// can record without a handle to the bot. Gauges that mirror bot state are refreshed
+// from the bot on every scrape.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{LazyLock, Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::*;
+
+const METRICS_REQUEST_MAX: usize = 8192;
+// in milliseconds
+const METRICS_REQUEST_TIMEOUT: u64 = 5000;
+const METRICS_ACCEPT_BACKOFF: u64 = 100;
+
+// in seconds
+const HISTOGRAM_BUCKETS: &[f64] = &[0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+// name, type, help
+const METRIC_INFO: &[(&str, &str, &str)] = &[
+    (
+        "sjmb_messages_received_total",
+        "counter",
+        "Channel and private messages received",
+    ),
+    ("sjmb_commands_total", "counter", "Command handlers executed"),
+    ("sjmb_queue_depth", "gauge", "Items waiting in a queue"),
+    (
+        "sjmb_queue_full_total",
+        "counter",
+        "Sends that waited on a full queue and were retried",
+    ),
+    (
+        "sjmb_queue_dropped_total",
+        "counter",
+        "Items dropped because their queue was full",
+    ),
+    ("sjmb_queue_wait_seconds", "histogram", "Time from queueing to sending"),
+    ("sjmb_url_fetch_seconds", "histogram", "URL fetch latency"),
+    ("sjmb_url_fetch_errors_total", "counter", "Failed URL fetches"),
+    ("sjmb_db_insert_retries_total", "counter", "Retried URL log inserts"),
+    (
+        "sjmb_db_insert_failures_total",
+        "counter",
+        "URL log inserts that failed after all retries",
+    ),
+    (
+        "sjmb_reconnects_total",
+        "counter",
+        "Lost connections, by the reason of the disconnect",
+    ),
+    ("sjmb_lag_seconds", "gauge", "Round-trip lag to the server"),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // e.g. "127.0.0.1:9184", empty disables the listener
+    pub listen: String,
+}
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    // cumulative counts per HISTOGRAM_BUCKETS
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    gauges: BTreeMap<(&'static str, Labels), f64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+fn labels(labels: &[(&str, &str)]) -> Labels {
+    labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, l: &[(&str, &str)]) {
+        *self.lock().counters.entry((name, labels(l))).or_default() += 1;
+    }
+
+    pub fn set(&self, name: &'static str, l: &[(&str, &str)], value: f64) {
+        self.lock().gauges.insert((name, labels(l)), value);
+    }
+
+    pub fn observe(&self, name: &'static str, l: &[(&str, &str)], value: f64) {
+        let mut registry = self.lock();
+        let h = registry.histograms.entry((name, labels(l))).or_default();
+        h.buckets.resize(HISTOGRAM_BUCKETS.len(), 0);
+        for (bucket, le) in h.buckets.iter_mut().zip(HISTOGRAM_BUCKETS) {
+            if value <= *le {
+                *bucket += 1;
+            }
+        }
+        h.sum += value;
+        h.count += 1;
+    }
+
+    pub fn observe_since(&self, name: &'static str, l: &[(&str, &str)], started: Instant) {
+        self.observe(name, l, started.elapsed().as_secs_f64());
+    }
+
+    // Text exposition format
+    pub fn render(&self) -> String {
+        let registry = self.lock();
+        let mut out = String::new();
+        for (name, kind, help) in METRIC_INFO {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for ((_, l), v) in registry.counters.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(out, "{name}{} {v}", fmt_labels(l, None));
+            }
+            for ((_, l), v) in registry.gauges.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(out, "{name}{} {v}", fmt_labels(l, None));
+            }
+            for ((_, l), h) in registry.histograms.iter().filter(|((n, _), _)| n == name) {
+                for (le, cnt) in HISTOGRAM_BUCKETS.iter().zip(&h.buckets) {
+                    let _ = writeln!(out, "{name}_bucket{} {cnt}", fmt_labels(l, Some(&le.to_string())));
+                }
+                let _ = writeln!(out, "{name}_bucket{} {}", fmt_labels(l, Some("+Inf")), h.count);
+                let _ = writeln!(out, "{name}_sum{} {}", fmt_labels(l, None), h.sum);
+                let _ = writeln!(out, "{name}_count{} {}", fmt_labels(l, None), h.count);
+            }
+        }
+        out
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn fmt_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut parts = labels
+        .iter()
+        .map(|(k, v)| {
+            format!(
+                "{k}=\"{}\"",
+                v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+            )
+        })
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{le}\""));
+    }
+    match parts.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", parts.join(",")),
+    }
+}
+
+pub async fn start_metrics_server(bot: Arc<IrcBot>) -> anyhow::Result<()> {
+    let listen = bot.config.read().await.metrics.listen.clone();
+    if listen.is_empty() {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(&listen).await?;
+    info!("Serving metrics on http://{listen}/metrics");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_metrics(bot.clone(), stream));
+                }
+                Err(e) => {
+                    error!("Metrics listener: {e}");
+                    // e.g. out of file descriptors, do not spin
+                    sleep(Duration::from_millis(METRICS_ACCEPT_BACKOFF)).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn serve_metrics(bot: Arc<IrcBot>, mut stream: TcpStream) {
+    let request = tokio::time::timeout(
+        Duration::from_millis(METRICS_REQUEST_TIMEOUT),
+        read_request_head(&mut stream),
+    )
+    .await;
+    let response = match request {
+        Ok(Ok(head)) if head.starts_with("GET /metrics ") => {
+            bot.update_metrics().await;
+            http_response("200 OK", "text/plain; version=0.0.4", &metrics().render())
+        }
+        Ok(Ok(_)) => http_response("404 Not Found", "text/plain", "Not found\n"),
+        Ok(Err(e)) => {
+            debug!("Metrics request: {e}");
+            return;
+        }
+        Err(_) => return,
+    };
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("Metrics response: {e}");
+    }
+}
+
+// Everything up to the empty line, the body of a GET is ignored
+async fn read_request_head(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > METRICS_REQUEST_MAX {
+            bail!("request too large");
+        }
+        match stream.read(&mut chunk).await? {
+            0 => break,
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render_in_text_format() {
+        let m = Metrics::default();
+        m.inc("sjmb_commands_total", &[("command", "!dice")]);
+        m.inc("sjmb_commands_total", &[("command", "!dice")]);
+        m.set("sjmb_lag_seconds", &[], 0.25);
+        m.observe("sjmb_url_fetch_seconds", &[], 0.2);
+
+        let text = m.render();
+        assert!(text.contains("# TYPE sjmb_commands_total counter\n"));
+        assert!(text.contains("sjmb_commands_total{command=\"!dice\"} 2\n"));
+        assert!(text.contains("sjmb_lag_seconds 0.25\n"));
+        assert!(text.contains("sjmb_url_fetch_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(text.contains("sjmb_url_fetch_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(text.contains("sjmb_url_fetch_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("sjmb_url_fetch_seconds_count 1\n"));
+    }
+}
+
+// EOF
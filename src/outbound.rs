@@ -0,0 +1,823 @@
+// outbound.rs
+
+// Every PRIVMSG, NOTICE and IRC op the bot sends goes through here, so the throttling,
+// logging and per-target accounting happen in one place.
+//
+// Sending is paced by a token bucket shared by all output. Waiting items are kept per
+// priority and per target: the highest priority goes first, and within a priority the
+// targets take turns so one busy channel cannot starve another. Low priority items that
+// have waited longer than `low_max_age` are dropped instead of being sent late.
+//
+// Messages are split to fit the 512 byte IRC line as the server relays it to others,
+// prefixed with our own `:nick!user@host`.
+//
+// The queues outlive the connection: while disconnected, items wait for the next one.
+// Sending resumes once the server has accepted our registration, and items for the
+// channels we are rejoining wait until we are back on them (or OUTBOUND_REJOIN_WAIT).
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use tokio::sync::mpsc::error::TryRecvError;
+
+use crate::*;
+
+// items waiting to be picked up by the scheduler, on top of `queue_capacity`
+const OUTBOUND_INTAKE: usize = 8;
+// in milliseconds, how often to check for a new connection while disconnected
+const OUTBOUND_DISCONNECTED_POLL: u64 = 500;
+// in milliseconds, how long to hold back a channel's items for a rejoin that does not happen
+const OUTBOUND_REJOIN_WAIT: u64 = 30_000;
+
+// in bytes, including the trailing CRLF
+const IRC_LINE_MAX: usize = 512;
+// assumed until we have seen our own user and host, USERLEN with a `~` and HOSTLEN
+const IRC_USER_MAX: usize = 11;
+const IRC_HOST_MAX: usize = 63;
+// never split into smaller pieces than this, even with very long targets
+const IRC_TEXT_MIN: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutboundConfig {
+    // a token is added every `interval` ms, plus a random jitter of up to `jitter` ms
+    pub interval: u64,
+    pub jitter: u64,
+    // max tokens saved up for a burst
+    pub burst: u32,
+    // max items waiting in the scheduler before new ones have to wait
+    pub queue_capacity: usize,
+    // low priority items are dropped after waiting this many ms, 0 keeps them forever
+    pub low_max_age: u64,
+    // max lines a single message is split into, the rest is cut off
+    pub max_lines: usize,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            interval: 1500,
+            jitter: 500,
+            burst: 3,
+            queue_capacity: 42,
+            low_max_age: 30000,
+            max_lines: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum OutPriority {
+    // mode ops and replies to privileged commands
+    High,
+    Normal,
+    // automatic output like URL titles
+    Low,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum OutKind {
+    Privmsg,
+    Notice,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutMsg {
+    pub kind: OutKind,
+    pub target: String,
+    pub msg: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum OutItem {
+    Msg(OutMsg),
+    Op(IrcOp),
+}
+
+impl OutItem {
+    fn target(&self) -> &str {
+        match self {
+            OutItem::Msg(m) => &m.target,
+            OutItem::Op(op) => op.target(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Queued {
+    item: OutItem,
+    prio: OutPriority,
+    queued_at: Instant,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TargetStats {
+    pub queued: u64,
+    pub sent: u64,
+    pub failed: u64,
+    // stale low priority items
+    pub dropped: u64,
+    pub bytes: u64,
+    // unix timestamp of the last successful send
+    pub last_sent: Option<i64>,
+}
+
+#[derive(Default)]
+struct OutboundShared {
+    // None while disconnected
+    irc_sender: Option<Sender>,
+    // the queues are only sent after RPL_WELCOME
+    registered: bool,
+    // lowercased channels we are rejoining, their items wait until then or held_until
+    held: HashSet<String>,
+    held_until: Option<Instant>,
+    my_nick: String,
+    // as seen by others, learned from our own JOINs
+    my_user: Option<String>,
+    my_host: Option<String>,
+    cfg: OutboundConfig,
+    // our sent messages for the plugins, see IrcBot::run()
+    own_events: Option<mpsc::Sender<LogEvent>>,
+    // queued items not yet sent or dropped
+    pending: usize,
+    // keyed by lowercased target
+    targets: HashMap<String, TargetStats>,
+}
+
+#[derive(Clone)]
+pub struct Outbound {
+    sender: mpsc::Sender<Queued>,
+    shared: Arc<Mutex<OutboundShared>>,
+}
+
+impl Outbound {
+    pub(crate) fn start(
+        channel_modes: Arc<RwLock<ChannelModes>>,
+        cfg: OutboundConfig,
+        irc_log: IrcLogger,
+        own_events: mpsc::Sender<LogEvent>,
+    ) -> Self {
+        let (sender, rx) = mpsc::channel::<Queued>(OUTBOUND_INTAKE);
+        let shared = Arc::new(Mutex::new(OutboundShared {
+            cfg,
+            own_events: Some(own_events),
+            ..Default::default()
+        }));
+
+        let sched_shared = shared.clone();
+        tokio::spawn(async move {
+            debug!("Starting outbound scheduler");
+            run_scheduler(channel_modes, sched_shared, irc_log, rx).await;
+        });
+
+        Self { sender, shared }
+    }
+
+    // A new connection, only raw commands like PING and QUIT go out until connected()
+    pub(crate) fn attach(&self, irc_sender: Sender) {
+        let mut shared = self.lock();
+        shared.irc_sender = Some(irc_sender);
+        shared.registered = false;
+    }
+
+    // Registered, sending starts or resumes and our prefix is learned again. The items for
+    // `rejoining` wait until joined() is called for the channel.
+    pub(crate) fn connected(&self, my_nick: &str, rejoining: &[String]) {
+        let mut shared = self.lock();
+        shared.registered = true;
+        shared.my_nick = my_nick.to_string();
+        shared.my_user = None;
+        shared.my_host = None;
+        shared.held = rejoining.iter().map(|c| c.to_lowercase()).collect();
+        shared.held_until = Some(Instant::now() + Duration::from_millis(OUTBOUND_REJOIN_WAIT));
+    }
+
+    pub(crate) fn joined(&self, channel: &str) {
+        self.lock().held.remove(&channel.to_lowercase());
+    }
+
+    pub(crate) fn disconnected(&self) {
+        let mut shared = self.lock();
+        shared.irc_sender = None;
+        shared.registered = false;
+        shared.held.clear();
+    }
+
+    pub async fn send(&self, prio: OutPriority, kind: OutKind, target: &str, msg: &str) -> anyhow::Result<()> {
+        let lines = {
+            let shared = self.lock();
+            let budget = line_budget(&shared, kind, target);
+            let lines = split_msg(msg, budget, shared.cfg.max_lines);
+            for line in &lines {
+                match kind {
+                    OutKind::Privmsg => info!("{target} <{}> {line}", shared.my_nick),
+                    OutKind::Notice => info!("{target} -{}- {line}", shared.my_nick),
+                }
+            }
+            lines
+        };
+        for line in lines {
+            let out = OutMsg {
+                kind,
+                target: target.to_string(),
+                msg: line,
+            };
+            self.enqueue(OutItem::Msg(out), prio).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn privmsg(&self, target: &str, msg: &str) -> anyhow::Result<()> {
+        self.send(OutPriority::Normal, OutKind::Privmsg, target, msg).await
+    }
+
+    pub async fn notice(&self, target: &str, msg: &str) -> anyhow::Result<()> {
+        self.send(OutPriority::Normal, OutKind::Notice, target, msg).await
+    }
+
+    pub async fn op(&self, op: IrcOp) -> anyhow::Result<()> {
+        self.enqueue(OutItem::Op(op), OutPriority::High).await
+    }
+
+    // Snapshot of the per-target counters
+    pub fn stats(&self) -> HashMap<String, TargetStats> {
+        self.lock().targets.clone()
+    }
+
+    pub(crate) fn set_nick(&self, nick: &str) {
+        self.lock().my_nick = nick.to_string();
+    }
+
+    pub(crate) fn set_user(&self, user: &str) {
+        self.lock().my_user = Some(user.to_string());
+    }
+
+    pub(crate) fn set_host(&self, host: &str) {
+        self.lock().my_host = Some(host.to_string());
+    }
+
+    // Returns false if items were still waiting at the deadline
+    pub async fn flush(&self, deadline: Instant) -> bool {
+        wait_until(deadline, || self.lock().pending == 0).await
+    }
+
+    // Queued or waiting items, not yet sent
+    pub fn pending(&self) -> usize {
+        self.lock().pending
+    }
+
+    // Sent right away past the queues, for protocol messages like PING
+    pub(crate) fn send_raw(&self, cmd: Command) -> anyhow::Result<()> {
+        let irc_sender = self.lock().irc_sender.clone().ok_or_else(|| anyhow!("Not connected"))?;
+        irc_sender.send(cmd)?;
+        Ok(())
+    }
+
+    // Sent right away, this is the last thing we say
+    pub fn quit(&self, reason: &str) -> anyhow::Result<()> {
+        info!("Quitting: {reason}");
+        let irc_sender = self.lock().irc_sender.clone().ok_or_else(|| anyhow!("Not connected"))?;
+        irc_sender.send_quit(reason)?;
+        Ok(())
+    }
+
+    // Rate limits are picked up by the scheduler before its next send
+    pub(crate) fn set_config(&self, cfg: OutboundConfig) {
+        self.lock().cfg = cfg;
+    }
+
+    async fn enqueue(&self, item: OutItem, prio: OutPriority) -> anyhow::Result<()> {
+        {
+            let mut shared = self.lock();
+            shared.pending += 1;
+            shared.targets.entry(item.target().to_lowercase()).or_default().queued += 1;
+        }
+        let queued = Queued {
+            item,
+            prio,
+            queued_at: Instant::now(),
+        };
+        let res = queue_send(&self.sender, queued, "outbound").await;
+        if res.is_err() {
+            self.lock().pending -= 1;
+        }
+        res
+    }
+
+    fn lock(&self) -> MutexGuard<'_, OutboundShared> {
+        lock_shared(&self.shared)
+    }
+}
+
+fn lock_shared(shared: &Mutex<OutboundShared>) -> MutexGuard<'_, OutboundShared> {
+    // the counters stay usable even if a holder panicked
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Bytes left for the text in `:nick!user@host PRIVMSG target :text\r\n`
+fn line_budget(shared: &OutboundShared, kind: OutKind, target: &str) -> usize {
+    let command = match kind {
+        OutKind::Privmsg => "PRIVMSG",
+        OutKind::Notice => "NOTICE",
+    };
+    let user = shared.my_user.as_ref().map_or(IRC_USER_MAX, String::len);
+    let host = shared.my_host.as_ref().map_or(IRC_HOST_MAX, String::len);
+    let overhead = format!(":{}!@ {command} {target} :\r\n", shared.my_nick).len() + user + host;
+    IRC_LINE_MAX.saturating_sub(overhead).max(IRC_TEXT_MIN)
+}
+
+// Splits text into lines of at most `budget` bytes, at spaces where possible and never inside
+// an UTF-8 sequence. Newlines always start a new line. Lines past `max_lines` are cut off and
+// the last one kept ends with "...".
+pub fn split_msg(msg: &str, budget: usize, max_lines: usize) -> Vec<String> {
+    let budget = budget.max(4);
+    let mut lines = Vec::new();
+
+    for mut rest in msg.split(['\r', '\n']).map(str::trim) {
+        while rest.len() > budget {
+            let cut = floor_char_boundary(rest, budget);
+            // a space right after the budget still lets the line fill it
+            let space = rest.as_bytes()[..=cut].iter().rposition(|&b| b == b' ');
+            let (line, next) = match space {
+                Some(space) if space > 0 => (&rest[..space], &rest[space + 1..]),
+                _ => (&rest[..cut], &rest[cut..]),
+            };
+            let line = line.trim_end();
+            if !line.is_empty() {
+                lines.push(line.to_string());
+            }
+            rest = next.trim_start();
+        }
+        if !rest.is_empty() {
+            lines.push(rest.to_string());
+        }
+    }
+
+    let max_lines = max_lines.max(1);
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        if let Some(last) = lines.last_mut() {
+            last.truncate(floor_char_boundary(last, budget - 3));
+            last.push_str("...");
+        }
+    }
+    lines
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    if i >= s.len() {
+        return s.len();
+    }
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+#[derive(Debug, Default)]
+struct TargetQueues {
+    // round-robin order of the targets that have something waiting
+    turns: VecDeque<String>,
+    queues: HashMap<String, VecDeque<Queued>>,
+}
+
+#[derive(Debug, Default)]
+struct Backlog {
+    // indexed by OutPriority
+    prios: [TargetQueues; 3],
+    len: usize,
+}
+
+impl Backlog {
+    fn push(&mut self, q: Queued) {
+        let key = q.item.target().to_lowercase();
+        let tq = &mut self.prios[q.prio as usize];
+        let queue = tq.queues.entry(key.clone()).or_default();
+        if queue.is_empty() {
+            tq.turns.push_back(key);
+        }
+        queue.push_back(q);
+        self.len += 1;
+    }
+
+    // Highest priority first, the targets taking turns within a priority. Of the `held`
+    // targets only the JOINs go, ahead of the rest.
+    fn pop(&mut self, held: &HashSet<String>) -> Option<Queued> {
+        for tq in &mut self.prios {
+            for _ in 0..tq.turns.len() {
+                let Some(key) = tq.turns.pop_front() else {
+                    break;
+                };
+                let Some(queue) = tq.queues.get_mut(&key) else {
+                    continue;
+                };
+                let q = match held.contains(&key) {
+                    true => queue
+                        .iter()
+                        .position(|q| matches!(q.item, OutItem::Op(IrcOp::Join(_))))
+                        .and_then(|i| queue.remove(i)),
+                    false => queue.pop_front(),
+                };
+                if queue.is_empty() {
+                    tq.queues.remove(&key);
+                } else {
+                    tq.turns.push_back(key);
+                }
+                if q.is_some() {
+                    self.len -= 1;
+                    return q;
+                }
+            }
+        }
+        None
+    }
+
+    // Removes the low priority items that have waited longer than max_age
+    fn drop_stale(&mut self, max_age: Duration, now: Instant) -> Vec<Queued> {
+        let TargetQueues { turns, queues } = &mut self.prios[OutPriority::Low as usize];
+        let mut dropped = Vec::new();
+        for queue in queues.values_mut() {
+            // oldest first, so the stale ones are at the front
+            let fresh = queue
+                .iter()
+                .position(|q| now.duration_since(q.queued_at) <= max_age)
+                .unwrap_or(queue.len());
+            dropped.extend(queue.drain(..fresh));
+        }
+        queues.retain(|_, queue| !queue.is_empty());
+        turns.retain(|key| queues.contains_key(key));
+        self.len -= dropped.len();
+        dropped
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: u32,
+    // when the next token is added, while not full
+    next_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(cfg: &OutboundConfig, now: Instant) -> Self {
+        Self {
+            tokens: cfg.burst.max(1),
+            next_refill: now,
+        }
+    }
+
+    fn refill(&mut self, cfg: &OutboundConfig, now: Instant) {
+        let burst = cfg.burst.max(1);
+        while self.tokens < burst && self.next_refill <= now {
+            self.tokens += 1;
+            self.next_refill += refill_interval(cfg);
+        }
+        self.tokens = self.tokens.min(burst);
+    }
+
+    // How long until a token is available
+    fn wait(&mut self, cfg: &OutboundConfig, now: Instant) -> Duration {
+        self.refill(cfg, now);
+        match self.tokens {
+            0 => self.next_refill.saturating_duration_since(now),
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn take(&mut self, cfg: &OutboundConfig, now: Instant) {
+        // a full bucket does not refill, start the clock when the first token goes
+        if self.tokens >= cfg.burst.max(1) {
+            self.next_refill = now + refill_interval(cfg);
+        }
+        self.tokens = self.tokens.saturating_sub(1);
+    }
+}
+
+fn refill_interval(cfg: &OutboundConfig) -> Duration {
+    let jitter = match cfg.jitter {
+        0 => 0,
+        j => rand::random_range(0..=j),
+    };
+    Duration::from_millis(cfg.interval + jitter)
+}
+
+async fn run_scheduler(
+    channel_modes: Arc<RwLock<ChannelModes>>,
+    shared: Arc<Mutex<OutboundShared>>,
+    irc_log: IrcLogger,
+    mut rx: mpsc::Receiver<Queued>,
+) {
+    let mut backlog = Backlog::default();
+    let mut bucket = TokenBucket::new(&lock_shared(&shared).cfg, Instant::now());
+    let mut closed = false;
+
+    loop {
+        let cfg = lock_shared(&shared).cfg.clone();
+
+        // take in everything that is already waiting, up to the capacity
+        while !closed && backlog.len < cfg.queue_capacity {
+            match rx.try_recv() {
+                Ok(q) => backlog.push(q),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => closed = true,
+            }
+        }
+
+        let now = Instant::now();
+        if cfg.low_max_age > 0 {
+            for q in backlog.drop_stale(Duration::from_millis(cfg.low_max_age), now) {
+                warn!(
+                    "Dropping stale outbound item after {} ms: {:?}",
+                    now.duration_since(q.queued_at).as_millis(),
+                    q.item
+                );
+                let mut shared = lock_shared(&shared);
+                shared.pending -= 1;
+                shared
+                    .targets
+                    .entry(q.item.target().to_lowercase())
+                    .or_default()
+                    .dropped += 1;
+            }
+        }
+
+        if backlog.len == 0 {
+            if closed {
+                break;
+            }
+            match rx.recv().await {
+                Some(q) => backlog.push(q),
+                None => break,
+            }
+            continue;
+        }
+
+        let (irc_sender, held) = {
+            let mut shared = lock_shared(&shared);
+            if shared.held_until.is_some_and(|until| now >= until) {
+                shared.held.clear();
+            }
+            let irc_sender = shared.irc_sender.clone().filter(|_| shared.registered);
+            (irc_sender, shared.held.clone())
+        };
+        let mut wait = match irc_sender {
+            Some(_) => bucket.wait(&cfg, now),
+            None => Duration::from_millis(OUTBOUND_DISCONNECTED_POLL),
+        };
+        if wait.is_zero()
+            && let Some(irc_sender) = &irc_sender
+        {
+            match backlog.pop(&held) {
+                Some(q) => {
+                    if send_item(irc_sender, &channel_modes, &shared, &irc_log, q).await {
+                        bucket.take(&cfg, Instant::now());
+                    }
+                    continue;
+                }
+                // only items for channels we are not back on yet
+                None => wait = Duration::from_millis(OUTBOUND_DISCONNECTED_POLL),
+            }
+        }
+        if closed || backlog.len >= cfg.queue_capacity {
+            sleep(wait).await;
+        } else {
+            tokio::select! {
+                Some(q) = rx.recv() => backlog.push(q),
+                _ = sleep(wait) => {}
+            }
+        }
+    }
+    debug!("Outbound scheduler stopped");
+}
+
+// Returns false if nothing was sent, so no token is used
+async fn send_item(
+    irc_sender: &Sender,
+    channel_modes: &RwLock<ChannelModes>,
+    shared: &Mutex<OutboundShared>,
+    irc_log: &IrcLogger,
+    q: Queued,
+) -> bool {
+    debug!("outbound: sending {q:?}");
+    metrics().observe_since("sjmb_queue_wait_seconds", &[("queue", "outbound")], q.queued_at);
+    let key = q.item.target().to_lowercase();
+    let (res, bytes) = match q.item {
+        OutItem::Msg(m) => {
+            let bytes = m.msg.len() as u64;
+            let res = match m.kind {
+                OutKind::Privmsg => irc_sender.send_privmsg(&m.target, &m.msg),
+                OutKind::Notice => irc_sender.send_notice(&m.target, &m.msg),
+            };
+            if res.is_ok() {
+                log_own_msg(shared, irc_log, m);
+            }
+            (res.map(|_| true).map_err(anyhow::Error::from), bytes)
+        }
+        OutItem::Op(op) => (op_send(irc_sender, channel_modes, op).await, 0),
+    };
+
+    let mut shared = lock_shared(shared);
+    shared.pending -= 1;
+    let stats = shared.targets.entry(key).or_default();
+    match res {
+        Ok(false) => false,
+        Ok(true) => {
+            stats.sent += 1;
+            stats.bytes += bytes;
+            stats.last_sent = Some(Utc::now().timestamp());
+            true
+        }
+        Err(e) => {
+            stats.failed += 1;
+            error!("{e}");
+            true
+        }
+    }
+}
+
+// The server does not echo our messages back, so they are logged as sent and handed to
+// the plugins like the messages of others
+fn log_own_msg(shared: &Mutex<OutboundShared>, irc_log: &IrcLogger, m: OutMsg) {
+    let (nick, userhost, own_events) = {
+        let shared = lock_shared(shared);
+        let userhost = format!(
+            "{}@{}",
+            shared.my_user.as_deref().unwrap_or_default(),
+            shared.my_host.as_deref().unwrap_or_default()
+        );
+        (shared.my_nick.clone(), userhost, shared.own_events.clone())
+    };
+    let event = LogEvent {
+        ts: Utc::now(),
+        target: m.target,
+        kind: match m.kind {
+            OutKind::Privmsg => LogKind::Message,
+            OutKind::Notice => LogKind::Notice,
+        },
+        nick,
+        userhost,
+        account: None,
+        tags: Vec::new(),
+        text: m.msg,
+    };
+    irc_log.log(event.clone());
+    // the scheduler does not wait for the plugins
+    if let Some(own_events) = own_events
+        && let Err(e) = own_events.try_send(event)
+    {
+        metrics().inc("sjmb_queue_dropped_total", &[("queue", "own_events")]);
+        warn!("Own message not passed to the plugins: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(prio: OutPriority, target: &str, msg: &str, queued_at: Instant) -> Queued {
+        Queued {
+            item: OutItem::Msg(OutMsg {
+                kind: OutKind::Privmsg,
+                target: target.to_string(),
+                msg: msg.to_string(),
+            }),
+            prio,
+            queued_at,
+        }
+    }
+
+    fn pop_msg(backlog: &mut Backlog) -> String {
+        pop_msg_held(backlog, &HashSet::new())
+    }
+
+    fn pop_msg_held(backlog: &mut Backlog, held: &HashSet<String>) -> String {
+        match backlog.pop(held).map(|q| q.item) {
+            Some(OutItem::Msg(m)) => m.msg,
+            other => panic!("expected a message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn backlog_orders_by_priority_and_takes_turns() {
+        let now = Instant::now();
+        let mut backlog = Backlog::default();
+        backlog.push(queued(OutPriority::Low, "#a", "title", now));
+        backlog.push(queued(OutPriority::Normal, "#busy", "b1", now));
+        backlog.push(queued(OutPriority::Normal, "#busy", "b2", now));
+        backlog.push(queued(OutPriority::Normal, "#busy", "b3", now));
+        backlog.push(queued(OutPriority::Normal, "#quiet", "q1", now));
+        backlog.push(queued(OutPriority::High, "#a", "op", now));
+
+        let order = (0..6).map(|_| pop_msg(&mut backlog)).collect::<Vec<_>>();
+        assert_eq!(order, ["op", "b1", "q1", "b2", "b3", "title"]);
+        assert_eq!(backlog.len, 0);
+        assert!(backlog.pop(&HashSet::new()).is_none());
+    }
+
+    #[test]
+    fn backlog_holds_channels_but_not_their_joins() {
+        let now = Instant::now();
+        let mut backlog = Backlog::default();
+        backlog.push(queued(OutPriority::Normal, "#Rejoining", "later", now));
+        backlog.push(queued(OutPriority::High, "#rejoining", "op", now));
+        backlog.push(Queued {
+            item: OutItem::Op(IrcOp::Join("#rejoining".to_string())),
+            prio: OutPriority::High,
+            queued_at: now,
+        });
+        backlog.push(queued(OutPriority::Normal, "#other", "now", now));
+
+        let held = HashSet::from(["#rejoining".to_string()]);
+        assert!(matches!(
+            backlog.pop(&held).map(|q| q.item),
+            Some(OutItem::Op(IrcOp::Join(_)))
+        ));
+        assert_eq!(pop_msg_held(&mut backlog, &held), "now");
+        assert!(backlog.pop(&held).is_none());
+        assert_eq!(backlog.len, 2);
+
+        assert_eq!(pop_msg(&mut backlog), "op");
+        assert_eq!(pop_msg(&mut backlog), "later");
+    }
+
+    #[test]
+    fn backlog_drops_only_stale_low_priority() {
+        let now = Instant::now();
+        let old = now - Duration::from_secs(60);
+        let mut backlog = Backlog::default();
+        backlog.push(queued(OutPriority::Low, "#a", "old title", old));
+        backlog.push(queued(OutPriority::Low, "#a", "new title", now));
+        backlog.push(queued(OutPriority::Low, "#b", "old title", old));
+        backlog.push(queued(OutPriority::Normal, "#a", "old reply", old));
+
+        let dropped = backlog.drop_stale(Duration::from_secs(30), now);
+        assert_eq!(dropped.len(), 2);
+        assert_eq!(backlog.len, 2);
+        assert_eq!(pop_msg(&mut backlog), "old reply");
+        assert_eq!(pop_msg(&mut backlog), "new title");
+    }
+
+    #[test]
+    fn split_msg_breaks_at_words_and_char_boundaries() {
+        assert_eq!(split_msg("short one", 20, 4), ["short one"]);
+        assert_eq!(
+            split_msg("the quick brown fox jumps over", 15, 4),
+            ["the quick brown", "fox jumps over"]
+        );
+        assert_eq!(split_msg("first\r\n\nsecond", 20, 4), ["first", "second"]);
+
+        // no spaces, "ä" is two bytes and must not be cut in half
+        let lines = split_msg("ääääää", 5, 4);
+        assert_eq!(lines, ["ää", "ää", "ää"]);
+    }
+
+    #[test]
+    fn split_msg_caps_lines() {
+        let lines = split_msg("aaaa bbbb cccc dddd eeee", 9, 2);
+        assert_eq!(lines, ["aaaa bbbb", "cccc d..."]);
+        assert!(lines.iter().all(|l| l.len() <= 9));
+    }
+
+    #[test]
+    fn line_budget_uses_own_prefix() {
+        let mut shared = OutboundShared {
+            my_nick: "sjmb".to_string(),
+            ..Default::default()
+        };
+        // 512 - len(":sjmb!@ PRIVMSG #chan :\r\n") - worst case user and host
+        assert_eq!(line_budget(&shared, OutKind::Privmsg, "#chan"), 512 - 25 - 11 - 63);
+
+        shared.my_user = Some("~sjmb".to_string());
+        shared.my_host = Some("example.com".to_string());
+        let prefix = ":sjmb!~sjmb@example.com PRIVMSG #chan :\r\n";
+        assert_eq!(line_budget(&shared, OutKind::Privmsg, "#chan"), 512 - prefix.len());
+    }
+
+    #[test]
+    fn token_bucket_allows_burst_then_paces() {
+        let cfg = OutboundConfig {
+            interval: 1000,
+            jitter: 0,
+            burst: 2,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&cfg, now);
+
+        for _ in 0..2 {
+            assert_eq!(bucket.wait(&cfg, now), Duration::ZERO);
+            bucket.take(&cfg, now);
+        }
+        assert_eq!(bucket.wait(&cfg, now), Duration::from_millis(1000));
+
+        let later = now + Duration::from_millis(1000);
+        assert_eq!(bucket.wait(&cfg, later), Duration::ZERO);
+        bucket.take(&cfg, later);
+        assert_eq!(bucket.wait(&cfg, later), Duration::from_millis(1000));
+    }
+}
+
+// EOF
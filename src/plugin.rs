@@ -0,0 +1,129 @@
+// plugin.rs
+
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// in milliseconds
+pub const PLUGIN_TICK_INTERVAL: u64 = 1000;
+
+// A plugin's config section: the channels it works on and its command word, with the
+// plugin's own settings `T` next to them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, bound(deserialize = "T: PluginOpts + Deserialize<'de>"))]
+pub struct PluginConfig<T> {
+    pub channels: HashMap<String, bool>,
+    // empty disables the command
+    pub cmd: String,
+    #[serde(flatten)]
+    pub opts: T,
+}
+
+// The defaults of a plugin's own settings and its command word
+pub trait PluginOpts: Default {
+    const CMD: &'static str;
+}
+
+impl<T: PluginOpts> Default for PluginConfig<T> {
+    fn default() -> Self {
+        Self {
+            channels: HashMap::new(),
+            cmd: T::CMD.to_string(),
+            opts: T::default(),
+        }
+    }
+}
+
+impl<T> PluginConfig<T> {
+    pub fn enabled(&self, channel: &str) -> bool {
+        matches!(get_wild(&self.channels, channel), Some(true))
+    }
+}
+
+// Extension point for bot features. Every hook has a no-op default, so a plugin only
+// implements what it needs. Plugins are called in the order they were added.
+//
+// `on_message` and `on_join` return true if the plugin reacted to the message and
+// later plugins should not see it.
+pub trait BotPlugin: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Called once when the plugin is added to the bot, register any commands here
+    fn register<'a>(&'a self, _bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Called when the server has accepted our registration (RPL_WELCOME)
+    fn on_connect(&self, _bot: Arc<IrcBot>) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Called for channel and private messages that no registered command handled. A plugin
+    // that only records or counts returns false, so that the later plugins see the message too.
+    fn on_message(&self, _bot: Arc<IrcBot>, _ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    // Called for every JOIN, including our own
+    fn on_join(&self, _bot: Arc<IrcBot>, _ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async { Ok(false) })
+    }
+
+    // Called for every channel log event (messages, joins, parts, quits, nick changes, ...)
+    // on the worker of its channel, in order with the messages
+    fn on_event(&self, _bot: Arc<IrcBot>, _event: LogEvent) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Called every PLUGIN_TICK_INTERVAL ms while connected
+    fn on_tick(&self, _bot: Arc<IrcBot>) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Called after the runtime config was successfully reloaded
+    fn on_reload(&self, _bot: Arc<IrcBot>) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// The features that used to be hardwired into IrcBot in their historical order,
+// followed by the plugins that are enabled in the bot config. Passive recorders go
+// first, so a plugin that handles a message cannot hide it from them.
+pub fn builtin_plugins() -> Vec<Arc<dyn BotPlugin>> {
+    vec![
+        Arc::new(HistoryPlugin::new()),
+        Arc::new(SeenPlugin::new()),
+        Arc::new(TellPlugin::new()),
+        Arc::new(RemindPlugin),
+        Arc::new(KarmaPlugin::new()),
+        Arc::new(QuotePlugin),
+        Arc::new(FactoidPlugin),
+        Arc::new(AclOpsPlugin),
+        Arc::new(UrlCmdPlugin),
+        Arc::new(UrlLogPlugin),
+        Arc::new(UrlTitlePlugin),
+        Arc::new(ScriptPlugin::new()),
+        Arc::new(WasmPlugins::new()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plugin_config_defaults_per_field() {
+        let cfg: PluginConfig<KarmaConfig> = serde_json::from_str(r#"{"rank_count": 7}"#).unwrap();
+        assert_eq!(cfg.cmd, "!karma");
+        assert_eq!(cfg.opts.rank_count, 7);
+        assert_eq!(cfg.opts.min_interval, 30);
+
+        let cfg: PluginConfig<HistoryConfig> =
+            serde_json::from_str(r##"{"channels": {"#a": true}, "cmd": ""}"##).unwrap();
+        assert!(cfg.enabled("#a") && !cfg.enabled("#b"));
+        assert!(cfg.cmd.is_empty());
+        assert_eq!(cfg.opts.max_results, 3);
+    }
+}
+
+// EOF
@@ -0,0 +1,141 @@
+// plugins/acl_ops.rs
+
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// Auto-op on join and the open invite/+o/+v commands, all driven by the regex ACLs
+pub struct AclOpsPlugin;
+
+impl BotPlugin for AclOpsPlugin {
+    fn name(&self) -> &str {
+        "acl_ops"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let (cmd_invite, cmd_mode_o, cmd_mode_v) = {
+                let config = bot.config.read().await;
+                (
+                    config.cmd_invite.clone(),
+                    config.cmd_mode_o.clone(),
+                    config.cmd_mode_v.clone(),
+                )
+            };
+
+            // these can be used by anyone (open)
+            bot.register_privmsg_open(&cmd_invite, into_msg_handler(handle_open_cmd_invite))
+                .await;
+            bot.register_privmsg_open(&cmd_mode_o, into_msg_handler(handle_open_cmd_mode_o))
+                .await;
+            bot.register_privmsg_open(&cmd_mode_v, into_msg_handler(handle_open_cmd_mode_v))
+                .await;
+            Ok(())
+        })
+    }
+
+    fn on_join(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(handle_join(bot, ctx))
+    }
+}
+
+// Process channel join messages here and return true only if something was reacted upon
+async fn handle_join(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let channel = ctx.channel.unwrap_or_default();
+    let (nick, userhost) = (ctx.nick, ctx.userhost);
+    info!("JOIN <{nick}> {userhost} {channel}",);
+    if nick == ctx.my_nick {
+        // Ignore self join :p
+        return Ok(false);
+    }
+
+    let acl_resp = bot
+        .config
+        .read()
+        .await
+        .auto_o_acl_rt
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no auto_o_acl_rt"))?
+        .re_match(&userhost);
+    if let Some((i, s)) = acl_resp {
+        info!("JOIN auto-op: ACL match {userhost} at index {i}: {s}",);
+        bot.new_op(IrcOp::ModeOper(channel, nick)).await?;
+        return Ok(true);
+    }
+
+    // we did nothing
+    Ok(false)
+}
+
+async fn handle_open_cmd_invite(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let (nick, userhost) = (ctx.nick, ctx.userhost);
+    let channel = bot.config.read().await.channel.clone();
+
+    let acl_resp_u = bot
+        .config
+        .read()
+        .await
+        .invite_bl_userhost_rt
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no invite_bl_userhost_rt"))?
+        .re_match(&userhost);
+    if let Some((i, s)) = acl_resp_u {
+        info!("ACL match userhost \"{userhost}\" at index {i}: {s}");
+        info!("Userhost {userhost} is blacklisted. No invite today.");
+        return Ok(true);
+    }
+
+    let acl_resp_n = bot
+        .config
+        .read()
+        .await
+        .invite_bl_nick_rt
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no invite_bl_nick_rt"))?
+        .re_match(&nick);
+    if let Some((i, s)) = acl_resp_n {
+        info!("ACL match nick \"{nick}\" at index {i}: {s}");
+        info!("Nick {nick} is blacklisted. No invite today.");
+        return Ok(true);
+    }
+
+    info!("Inviting {nick} to {channel}");
+    bot.clone().new_op(IrcOp::Invite(nick, channel)).await
+}
+
+async fn handle_open_cmd_mode_o(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let (nick, userhost) = (ctx.nick, ctx.userhost);
+    let channel = bot.config.read().await.channel.clone();
+
+    let now1 = Utc::now();
+    let acl_resp = bot
+        .config
+        .read()
+        .await
+        .mode_o_acl_rt
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("no mode_o_acl_rt"))?
+        .re_match(&userhost);
+    debug!(
+        "ACL check took {} µs.",
+        Utc::now().signed_duration_since(now1).num_microseconds().unwrap_or(-1)
+    );
+
+    match acl_resp {
+        Some((i, s)) => {
+            info!("ACL match {userhost} at index {i}: {s}");
+            bot.new_op(IrcOp::ModeOper(channel, nick)).await
+        }
+        None => {
+            info!("ACL check failed for {userhost}. Fallback +v.");
+            bot.new_op(IrcOp::ModeVoice(channel, nick)).await
+        }
+    }
+}
+
+async fn handle_open_cmd_mode_v(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let channel = bot.config.read().await.channel.clone();
+    bot.new_op(IrcOp::ModeVoice(channel, ctx.nick)).await
+}
+
+// EOF
@@ -0,0 +1,401 @@
+// plugins/factoid.rs
+
+use std::sync::LazyLock;
+
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// infobot style, a value starting with this is replied as is instead of "<key> is <value>"
+const FACTOID_REPLY_PREFIX: &str = "<reply>";
+// in bytes, rendering stops with an error past this
+const FACTOID_RENDER_MAX: usize = 2048;
+
+// Anyone can write the values, so no Tera, just these substitutions
+static FACTOID_VAR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*(nick|channel|arg|args\[(\d+)\])\s*\}\}").expect("valid regex"));
+
+// The channels with their own factoids, the global ones are managed in private. The command
+// recalls a factoid, the others are below, and all work in channel and private messages.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FactoidConfig {
+    // empty disables a command
+    pub cmd_learn: String,
+    pub cmd_forget: String,
+    pub cmd_lock: String,
+    pub cmd_unlock: String,
+    pub cmd_info: String,
+    pub max_len: usize,
+    // edits shown by the info command
+    pub history_lines: i64,
+}
+
+impl Default for FactoidConfig {
+    fn default() -> Self {
+        Self {
+            cmd_learn: "!learn".to_string(),
+            cmd_forget: "!forget".to_string(),
+            cmd_lock: "!lock".to_string(),
+            cmd_unlock: "!unlock".to_string(),
+            cmd_info: "!factinfo".to_string(),
+            max_len: 400,
+            history_lines: 3,
+        }
+    }
+}
+
+impl PluginOpts for FactoidConfig {
+    const CMD: &'static str = "??";
+}
+
+// `!learn <key> = <value>`, `?? <key>` and `!forget <key>`. A factoid learned on a channel
+// belongs to it, and the global ones are learned in private by the privileged nicks, who
+// can also lock factoids, a locked global one also keeps the channels from overriding it.
+// Values can refer to the nick, the channel and the words after the key, see render_factoid().
+pub struct FactoidPlugin;
+
+impl BotPlugin for FactoidPlugin {
+    fn name(&self) -> &str {
+        "factoid"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let cmds = {
+                let factoid = &bot.config.read().await.factoid;
+                let cfg = &factoid.opts;
+                [
+                    (cfg.cmd_learn.clone(), into_msg_handler(handle_cmd_learn)),
+                    (factoid.cmd.clone(), into_msg_handler(handle_cmd_recall)),
+                    (cfg.cmd_forget.clone(), into_msg_handler(handle_cmd_forget)),
+                    (cfg.cmd_lock.clone(), into_msg_handler(handle_cmd_lock)),
+                    (cfg.cmd_unlock.clone(), into_msg_handler(handle_cmd_unlock)),
+                    (cfg.cmd_info.clone(), into_msg_handler(handle_cmd_info)),
+                ]
+            };
+            for (cmd, handler) in cmds {
+                if !cmd.is_empty() {
+                    bot.register_chanmsg(&cmd, handler.clone()).await;
+                    bot.register_privmsg_open(&cmd, handler).await;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+// The channel a command is about, "" for the global factoids in private. None if factoids
+// are not enabled on the channel.
+async fn factoid_scope(bot: &Arc<IrcBot>, ctx: &MsgContext) -> Option<String> {
+    match &ctx.channel {
+        Some(channel) => {
+            let cfg = bot.config.read().await;
+            cfg.factoid.enabled(channel).then(|| channel.clone())
+        }
+        None => Some(String::new()),
+    }
+}
+
+fn factoid_db(cfg: &BotConfig) -> anyhow::Result<DbCtx> {
+    cfg.db.clone().ok_or_else(|| anyhow!("No database pool for factoids"))
+}
+
+// Keys are single words, so that the rest of a `??` can be arguments
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains(char::is_whitespace)
+}
+
+async fn handle_cmd_learn(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(scope) = factoid_scope(&bot, &ctx).await else {
+        return Ok(false);
+    };
+    let Some((key, value)) = ctx
+        .args
+        .split_once('=')
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim()))
+        .filter(|(k, v)| valid_key(k) && !v.is_empty())
+    else {
+        ctx.reply(&bot, &format!("Usage: {} <key> = <value>", ctx.cmd)).await?;
+        return Ok(true);
+    };
+
+    let privileged = bot.is_privileged(&ctx.nick).await;
+    if scope.is_empty() && !privileged {
+        ctx.reply(&bot, "Only privileged users can learn global factoids.")
+            .await?;
+        return Ok(true);
+    }
+    let (db, max_len) = {
+        let cfg = bot.config.read().await;
+        (factoid_db(&cfg)?, cfg.factoid.opts.max_len)
+    };
+    let refusal = match value.len() > max_len {
+        true => Some(format!("That is longer than {max_len} characters.")),
+        // refuse what would not render
+        false => render_factoid(&key, value, &ctx, "")
+            .err()
+            .map(|e| format!("Template error: {e}")),
+    };
+    if let Some(refusal) = refusal {
+        ctx.reply(&bot, &refusal).await?;
+        return Ok(true);
+    }
+
+    // the channel one would hide a locked global one
+    let mut scopes = vec![scope.clone()];
+    if !scope.is_empty() {
+        scopes.push(String::new());
+    }
+    for s in &scopes {
+        if let Some(old) = db_get_factoid(&db, std::slice::from_ref(s), &key).await?
+            && old.locked
+            && !privileged
+        {
+            ctx.reply(&bot, &format!("{key} is locked.")).await?;
+            return Ok(true);
+        }
+    }
+    let change = FactoidChange::Learn(value);
+    db_change_factoid(&db, &scope, &key, change, &ctx.nick, Utc::now().timestamp()).await?;
+    info!("Factoid {key:?} on {scope:?} learned by {}: {value}", ctx.nick);
+    ctx.reply(&bot, &format!("OK, {key} learned.")).await?;
+    Ok(true)
+}
+
+async fn handle_cmd_recall(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(scope) = factoid_scope(&bot, &ctx).await else {
+        return Ok(false);
+    };
+    let args = ctx.args.trim();
+    let (key, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    if key.is_empty() {
+        ctx.reply(&bot, &format!("Usage: {} <key> [args]", ctx.cmd)).await?;
+        return Ok(true);
+    }
+
+    let db = factoid_db(&*bot.config.read().await)?;
+    let mut scopes = vec![String::new()];
+    if !scope.is_empty() {
+        scopes.push(scope);
+    }
+    let Some(factoid) = db_get_factoid(&db, &scopes, key).await? else {
+        ctx.reply(&bot, &format!("I do not know about {key}.")).await?;
+        return Ok(true);
+    };
+
+    let reply = match render_factoid(key, &factoid.value, &ctx, rest.trim()) {
+        Ok(reply) => reply,
+        Err(e) => {
+            error!("Factoid {key:?} does not render: {e}");
+            format!("{key} does not render.")
+        }
+    };
+    ctx.reply(&bot, &reply).await?;
+    Ok(true)
+}
+
+async fn handle_cmd_forget(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(scope) = factoid_scope(&bot, &ctx).await else {
+        return Ok(false);
+    };
+    let key = ctx.args.trim().to_lowercase();
+    if !valid_key(&key) {
+        ctx.reply(&bot, &format!("Usage: {} <key>", ctx.cmd)).await?;
+        return Ok(true);
+    }
+
+    let privileged = bot.is_privileged(&ctx.nick).await;
+    if scope.is_empty() && !privileged {
+        ctx.reply(&bot, "Only privileged users can forget global factoids.")
+            .await?;
+        return Ok(true);
+    }
+    let db = factoid_db(&*bot.config.read().await)?;
+    let reply = match db_get_factoid(&db, std::slice::from_ref(&scope), &key).await? {
+        None => format!("I do not know about {key}."),
+        Some(old) if old.locked && !privileged => format!("{key} is locked."),
+        Some(_) => {
+            let ts = Utc::now().timestamp();
+            db_change_factoid(&db, &scope, &key, FactoidChange::Forget, &ctx.nick, ts).await?;
+            info!("Factoid {key:?} on {scope:?} forgotten by {}", ctx.nick);
+            format!("OK, {key} forgotten.")
+        }
+    };
+    ctx.reply(&bot, &reply).await?;
+    Ok(true)
+}
+
+async fn handle_cmd_lock(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    factoid_set_lock(bot, ctx, FactoidChange::Lock).await
+}
+
+async fn handle_cmd_unlock(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    factoid_set_lock(bot, ctx, FactoidChange::Unlock).await
+}
+
+async fn factoid_set_lock(bot: Arc<IrcBot>, ctx: MsgContext, change: FactoidChange<'_>) -> anyhow::Result<bool> {
+    let Some(scope) = factoid_scope(&bot, &ctx).await else {
+        return Ok(false);
+    };
+    let key = ctx.args.trim().to_lowercase();
+    if !valid_key(&key) {
+        ctx.reply(&bot, &format!("Usage: {} <key>", ctx.cmd)).await?;
+        return Ok(true);
+    }
+    if !bot.is_privileged(&ctx.nick).await {
+        ctx.reply(&bot, "Only privileged users can lock factoids.").await?;
+        return Ok(true);
+    }
+
+    let db = factoid_db(&*bot.config.read().await)?;
+    let ts = Utc::now().timestamp();
+    let reply = match db_change_factoid(&db, &scope, &key, change, &ctx.nick, ts).await? {
+        true => {
+            info!("Factoid {key:?} on {scope:?} {change:?} by {}", ctx.nick);
+            match change {
+                FactoidChange::Lock => format!("OK, {key} locked."),
+                _ => format!("OK, {key} unlocked."),
+            }
+        }
+        false => format!("I do not know about {key}."),
+    };
+    ctx.reply(&bot, &reply).await?;
+    Ok(true)
+}
+
+// Who set the factoid that `??` would show, and its latest changes
+async fn handle_cmd_info(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(scope) = factoid_scope(&bot, &ctx).await else {
+        return Ok(false);
+    };
+    let key = ctx.args.trim().to_lowercase();
+    if !valid_key(&key) {
+        ctx.reply(&bot, &format!("Usage: {} <key>", ctx.cmd)).await?;
+        return Ok(true);
+    }
+
+    let (db, tz, history_lines) = {
+        let cfg = bot.config.read().await;
+        (
+            factoid_db(&cfg)?,
+            cfg.channel_tz(ctx.reply_target()),
+            cfg.factoid.opts.history_lines,
+        )
+    };
+    let mut scopes = vec![String::new()];
+    if !scope.is_empty() {
+        scopes.push(scope);
+    }
+    let Some(factoid) = db_get_factoid(&db, &scopes, &key).await? else {
+        ctx.reply(&bot, &format!("I do not know about {key}.")).await?;
+        return Ok(true);
+    };
+
+    let when = |ts: i64| {
+        DateTime::from_timestamp(ts, 0)
+            .map(|ts| ts.with_timezone(&tz).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default()
+    };
+    let place = match factoid.channel.is_empty() {
+        true => "global".to_string(),
+        false => format!("on {}", factoid.channel),
+    };
+    let locked = match factoid.locked {
+        true => ", locked",
+        false => "",
+    };
+    let mut lines = vec![format!(
+        "{key} ({place}{locked}) = {}, set by {} at {}",
+        factoid.value,
+        factoid.nick,
+        when(factoid.updated)
+    )];
+    for h in db_factoid_history(&db, &factoid.channel, &key, history_lines).await? {
+        lines.push(match h.action.as_str() {
+            "learn" => format!("  {} learn by {}: {}", when(h.ts), h.nick, h.value),
+            action => format!("  {} {action} by {}", when(h.ts), h.nick),
+        });
+    }
+    for line in lines {
+        ctx.reply(&bot, &line).await?;
+    }
+    Ok(true)
+}
+
+// The reply to `?? <key> <arg>`. Only plain substitutions of `{{ nick }}`, `{{ channel }}`,
+// `{{ arg }}` and `{{ args[N] }}`, so the output grows at most linearly with the value and
+// is cut off at FACTOID_RENDER_MAX.
+fn render_factoid(key: &str, value: &str, ctx: &MsgContext, arg: &str) -> anyhow::Result<String> {
+    if ["{{", "{%", "{#"]
+        .iter()
+        .any(|open| FACTOID_VAR_RE.replace_all(value, "").contains(open))
+    {
+        bail!("only {{{{ nick }}}}, {{{{ channel }}}}, {{{{ arg }}}} and {{{{ args[N] }}}} are allowed");
+    }
+    let args = arg.split_whitespace().collect::<Vec<&str>>();
+    let text = FACTOID_VAR_RE.replace_all(value, |caps: &regex::Captures| match &caps[1] {
+        "nick" => ctx.nick.as_str(),
+        "channel" => ctx.channel.as_deref().unwrap_or_default(),
+        "arg" => arg,
+        _ => caps[2]
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| args.get(i).copied())
+            .unwrap_or_default(),
+    });
+    if text.len() > FACTOID_RENDER_MAX {
+        bail!("the result is longer than {FACTOID_RENDER_MAX} bytes");
+    }
+    let text = text.ws_collapse();
+    Ok(match text.strip_prefix(FACTOID_REPLY_PREFIX) {
+        Some(reply) => reply.trim_start().to_string(),
+        None => format!("{key} is {text}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn factoid_templates() {
+        let ctx = MsgContext {
+            nick: "alice".to_string(),
+            channel: Some("#chan".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render_factoid("hi", "<reply> hi {{ nick }}, welcome to {{ channel }}", &ctx, "").unwrap(),
+            "hi alice, welcome to #chan"
+        );
+        assert_eq!(
+            render_factoid(
+                "first",
+                "{{args[1]}} before {{ args[0] }}, {{ args[5] }}all: {{ arg }}",
+                &ctx,
+                "Foo Bar"
+            )
+            .unwrap(),
+            "first is Bar before Foo, all: Foo Bar"
+        );
+        assert_eq!(
+            render_factoid("rust", "a  language\nwith crabs", &ctx, "").unwrap(),
+            "rust is a language with crabs"
+        );
+        assert!(render_factoid("broken", "{{ nick", &ctx, "").is_err());
+
+        assert!(render_factoid("filter", "{{ args[0] | slugify }}", &ctx, "").is_err());
+
+        // no loops or functions, and no more output than FACTOID_RENDER_MAX
+        let looping = "{% for i in range(end=100000) %}{% endfor %}";
+        assert!(render_factoid("busy", looping, &ctx, "").is_err());
+        let growing = "{{ range(end=99999) | join(sep=\"x\") | replace(from=\"x\", to=\"xxxx\") }}";
+        assert!(render_factoid("growing", growing, &ctx, "").is_err());
+        let long = "{{ arg }}".repeat(50);
+        assert!(render_factoid("long", &long, &ctx, &"x".repeat(100)).is_err());
+    }
+}
+
+// EOF
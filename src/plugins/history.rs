@@ -0,0 +1,159 @@
+// plugins/history.rs
+
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// The channels whose messages are stored for searching, the command works in channel and
+// private messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub max_results: i64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self { max_results: 3 }
+    }
+}
+
+impl PluginOpts for HistoryConfig {
+    const CMD: &'static str = "!grep";
+}
+
+// rows waiting for the database writer
+const HISTORY_QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug)]
+struct HistoryRow {
+    db: DbCtx,
+    ts: i64,
+    channel: String,
+    nick: String,
+    msg: String,
+}
+
+// Stores channel messages, the bot's own and the commands included, in the database and
+// searches them with `!grep <terms>`
+pub struct HistoryPlugin {
+    queue: mpsc::Sender<HistoryRow>,
+}
+
+impl HistoryPlugin {
+    // Starts the database writer, the channel workers only queue the rows for it
+    pub fn new() -> Self {
+        let (queue, mut rx) = mpsc::channel::<HistoryRow>(HISTORY_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(row) = rx.recv().await {
+                if let Err(e) = db_add_history(&row.db, row.ts, &row.channel, &row.nick, &row.msg).await {
+                    error!("History insert failed for {}: {e:#}", row.channel);
+                }
+            }
+        });
+        Self { queue }
+    }
+
+    async fn store(&self, bot: Arc<IrcBot>, event: LogEvent) -> anyhow::Result<()> {
+        if !is_channel_name(&event.target) {
+            return Ok(());
+        }
+        let msg = match event.kind {
+            LogKind::Message => event.text,
+            LogKind::Action => format!("/me {}", event.text),
+            _ => return Ok(()),
+        };
+
+        let db = {
+            let cfg = bot.config.read().await;
+            if !cfg.history.enabled(&event.target) {
+                return Ok(());
+            }
+            cfg.db.clone().ok_or_else(|| anyhow!("No database pool for history"))?
+        };
+
+        let row = HistoryRow {
+            db,
+            ts: event.ts.timestamp(),
+            channel: event.target,
+            nick: event.nick,
+            msg,
+        };
+        // waits only if the writer is far behind
+        queue_send(&self.queue, row, "history").await
+    }
+}
+
+impl Default for HistoryPlugin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BotPlugin for HistoryPlugin {
+    fn name(&self) -> &str {
+        "history"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let cmd_grep = bot.config.read().await.history.cmd.clone();
+            if !cmd_grep.is_empty() {
+                bot.register_chanmsg(&cmd_grep, into_msg_handler(handle_cmd_grep)).await;
+                bot.register_privmsg_open(&cmd_grep, into_msg_handler(handle_cmd_grep))
+                    .await;
+            }
+            Ok(())
+        })
+    }
+
+    fn on_event(&self, bot: Arc<IrcBot>, event: LogEvent) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(self.store(bot, event))
+    }
+}
+
+// In a channel only that channel is searched, in private all the searchable channels the
+// requester is on
+async fn handle_cmd_grep(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let terms = ctx.args.trim();
+    if terms.is_empty() {
+        ctx.reply(&bot, &format!("Usage: {} <terms>", ctx.cmd)).await?;
+        return Ok(true);
+    }
+
+    let candidates = match &ctx.channel {
+        Some(channel) => vec![channel.clone()],
+        None => bot.channels_of(&ctx.nick).await,
+    };
+    let (db, channels, limit) = {
+        let cfg = bot.config.read().await;
+        let channels = candidates
+            .into_iter()
+            .filter(|c| cfg.history.enabled(c))
+            .collect::<Vec<_>>();
+        (cfg.db.clone(), channels, cfg.history.opts.max_results)
+    };
+    let Some(db) = db else {
+        bail!("No database pool for history");
+    };
+    if channels.is_empty() {
+        ctx.reply(&bot, "No searchable channels here.").await?;
+        return Ok(true);
+    }
+
+    info!("History search by {} in {channels:?}: {terms}", ctx.nick);
+    let rows = db_grep_history(&db, &channels, terms, limit).await?;
+    if rows.is_empty() {
+        ctx.reply(&bot, "No matches.").await?;
+    }
+    for row in rows {
+        let line = match ctx.channel {
+            Some(_) => format!("[{}] <{}> {}", row.seen.ts_short(), row.nick, row.msg),
+            None => format!("[{}] {} <{}> {}", row.seen.ts_short(), row.channel, row.nick, row.msg),
+        };
+        ctx.reply(&bot, &line).await?;
+    }
+    Ok(true)
+}
+
+// EOF
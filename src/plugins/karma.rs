@@ -0,0 +1,202 @@
+// plugins/karma.rs
+
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use tokio::sync::Mutex;
+
+use crate::*;
+
+// things per message, the rest is ignored
+const KARMA_MAX_PER_MSG: usize = 3;
+
+// The channels where karma is counted, the command works in channel messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KarmaConfig {
+    // in seconds, how often one nick can give karma on a channel
+    pub min_interval: u64,
+    // how many `!karma top` and `!karma bottom` show
+    pub rank_count: i64,
+}
+
+impl Default for KarmaConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: 30,
+            rank_count: 5,
+        }
+    }
+}
+
+impl PluginOpts for KarmaConfig {
+    const CMD: &'static str = "!karma";
+}
+
+// Counts `thing++` and `thing--` per channel and answers `!karma <thing>`, `!karma top` and `!karma bottom`
+#[derive(Default)]
+pub struct KarmaPlugin {
+    // last karma given, by "channel nick"
+    last: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl KarmaPlugin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BotPlugin for KarmaPlugin {
+    fn name(&self) -> &str {
+        "karma"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let cmd_karma = bot.config.read().await.karma.cmd.clone();
+            if !cmd_karma.is_empty() {
+                bot.register_chanmsg(&cmd_karma, into_msg_handler(handle_cmd_karma))
+                    .await;
+            }
+            Ok(())
+        })
+    }
+
+    fn on_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(handle_karma(bot, ctx, self.last.clone()))
+    }
+}
+
+async fn handle_karma(
+    bot: Arc<IrcBot>,
+    ctx: MsgContext,
+    last: Arc<Mutex<HashMap<String, Instant>>>,
+) -> anyhow::Result<bool> {
+    let Some(channel) = &ctx.channel else {
+        return Ok(false);
+    };
+    // no patting your own back
+    let changes = karma_changes(&ctx.msg)
+        .into_iter()
+        .filter(|(thing, _)| !thing.eq_ignore_ascii_case(&ctx.nick))
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return Ok(false);
+    }
+
+    let (db, min_interval) = {
+        let cfg = bot.config.read().await;
+        if !cfg.karma.enabled(channel) {
+            return Ok(false);
+        }
+        (
+            cfg.db.clone().ok_or_else(|| anyhow!("No database pool for karma"))?,
+            Duration::from_secs(cfg.karma.opts.min_interval),
+        )
+    };
+
+    {
+        let now = Instant::now();
+        let mut last = last.lock().await;
+        last.retain(|_, t| now.duration_since(*t) < min_interval);
+        let key = format!("{} {}", channel.to_lowercase(), ctx.nick.to_lowercase());
+        if last.contains_key(&key) {
+            info!("Karma from {} on {channel} rate limited", ctx.nick);
+            return Ok(false);
+        }
+        last.insert(key, now);
+    }
+
+    let ts = Utc::now().timestamp();
+    for (thing, delta) in changes {
+        let score = db_add_karma(&db, ts, channel, &thing, delta).await?;
+        info!("Karma on {channel} by {}: {thing} {delta:+} = {score}", ctx.nick);
+    }
+
+    Ok(false)
+}
+
+async fn handle_cmd_karma(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(channel) = &ctx.channel else {
+        return Ok(false);
+    };
+    let (db, rank_count) = {
+        let cfg = bot.config.read().await;
+        if !cfg.karma.enabled(channel) {
+            return Ok(false);
+        }
+        (cfg.db.clone(), cfg.karma.opts.rank_count)
+    };
+    let Some(db) = db else {
+        bail!("No database pool for karma");
+    };
+
+    let reply = match ctx.args.split_whitespace().next() {
+        None => format!("Usage: {} <thing> | top | bottom", ctx.cmd),
+        Some(rank @ ("top" | "bottom")) => {
+            let rows = db_karma_rank(&db, channel, rank == "bottom", rank_count).await?;
+            match rows.is_empty() {
+                true => "No karma here yet.".to_string(),
+                false => karma_rank_line(&rows),
+            }
+        }
+        Some(thing) => match db_get_karma(&db, channel, thing).await? {
+            Some(k) => format!("{} has karma {}", k.thing, k.score),
+            None => format!("{thing} has no karma"),
+        },
+    };
+    ctx.reply(&bot, &reply).await?;
+    Ok(true)
+}
+
+fn karma_rank_line(rows: &[DbKarma]) -> String {
+    rows.iter()
+        .map(|k| format!("{} ({})", k.thing, k.score))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// The `thing++` and `thing--` words of a message, each thing once
+pub fn karma_changes(msg: &str) -> Vec<(String, i64)> {
+    let mut changes: Vec<(String, i64)> = Vec::new();
+    for word in msg.split_whitespace() {
+        let word = word.trim_end_matches([',', '.', ';', ':', '!', '?']);
+        let (thing, delta) = match (word.strip_suffix("++"), word.strip_suffix("--")) {
+            (Some(thing), _) => (thing, 1),
+            (_, Some(thing)) => (thing, -1),
+            _ => continue,
+        };
+        // e.g. "<--" and "+++" are not things
+        if thing.ends_with(['+', '-']) || !thing.chars().any(char::is_alphanumeric) {
+            continue;
+        }
+        if changes.iter().any(|(t, _)| t.eq_ignore_ascii_case(thing)) {
+            continue;
+        }
+        changes.push((thing.to_string(), delta));
+        if changes.len() == KARMA_MAX_PER_MSG {
+            break;
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn karma_words() {
+        assert_eq!(
+            karma_changes("rust++ and go--, thanks"),
+            [("rust".to_string(), 1), ("go".to_string(), -1)]
+        );
+        assert_eq!(karma_changes("C++ is fine"), [("C".to_string(), 1)]);
+        assert_eq!(karma_changes("coffee++ COFFEE++ coffee--"), [("coffee".to_string(), 1)]);
+        assert_eq!(karma_changes("a++ b++ c++ d++").len(), KARMA_MAX_PER_MSG);
+        assert!(karma_changes("look <-- there, ++ and --- and +++").is_empty());
+        assert!(karma_changes("i = i + 1").is_empty());
+    }
+}
+
+// EOF
@@ -0,0 +1,31 @@
+// plugins/mod.rs
+
+pub use acl_ops::*;
+pub use factoid::*;
+pub use history::*;
+pub use karma::*;
+pub use quote::*;
+pub use remind::*;
+pub use script::*;
+pub use seen::*;
+pub use tell::*;
+pub use url_cmd::*;
+pub use url_log::*;
+pub use url_title::*;
+pub use wasm::*;
+
+pub mod acl_ops;
+pub mod factoid;
+pub mod history;
+pub mod karma;
+pub mod quote;
+pub mod remind;
+pub mod script;
+pub mod seen;
+pub mod tell;
+pub mod url_cmd;
+pub mod url_log;
+pub mod url_title;
+pub mod wasm;
+
+// EOF
@@ -0,0 +1,146 @@
+// plugins/quote.rs
+
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// The channels with a quote database, the command works in channel messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QuoteConfig {
+    // for `!quote search`
+    pub max_results: i64,
+}
+
+impl Default for QuoteConfig {
+    fn default() -> Self {
+        Self { max_results: 3 }
+    }
+}
+
+impl PluginOpts for QuoteConfig {
+    const CMD: &'static str = "!quote";
+}
+
+// The per-channel quote database: `!quote add <text>`, `!quote <id>`, `!quote random`,
+// `!quote search <term>` and, for privileged nicks, `!quote del <id>`
+pub struct QuotePlugin;
+
+impl BotPlugin for QuotePlugin {
+    fn name(&self) -> &str {
+        "quote"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let cmd_quote = bot.config.read().await.quote.cmd.clone();
+            if !cmd_quote.is_empty() {
+                bot.register_chanmsg(&cmd_quote, into_msg_handler(handle_cmd_quote))
+                    .await;
+            }
+            Ok(())
+        })
+    }
+}
+
+async fn handle_cmd_quote(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(channel) = &ctx.channel else {
+        return Ok(false);
+    };
+    let (db, tz, max_results) = {
+        let cfg = bot.config.read().await;
+        if !cfg.quote.enabled(channel) {
+            return Ok(false);
+        }
+        (cfg.db.clone(), cfg.channel_tz(channel), cfg.quote.opts.max_results)
+    };
+    let Some(db) = db else {
+        bail!("No database pool for quotes");
+    };
+
+    let args = ctx.args.trim();
+    let (sub, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let rest = rest.trim();
+    let usage = || {
+        format!(
+            "Usage: {} add <text> | <id> | random | search <term> | del <id>",
+            ctx.cmd
+        )
+    };
+    let lines = match (sub, sub.trim_start_matches('#').parse::<i64>()) {
+        ("add", _) if !rest.is_empty() => {
+            let id = db_add_quote(&db, channel, Utc::now().timestamp(), &ctx.nick, rest).await?;
+            info!("Quote #{id} added on {channel} by {}", ctx.nick);
+            vec![format!("Added quote #{id}.")]
+        }
+        ("" | "random", _) => match db_random_quote(&db, channel).await? {
+            Some(q) => vec![quote_line(&q, tz)],
+            None => vec!["No quotes here yet.".to_string()],
+        },
+        ("search", _) if !rest.is_empty() => {
+            let quotes = db_search_quote(&db, channel, rest, max_results).await?;
+            match quotes.is_empty() {
+                true => vec!["No matches.".to_string()],
+                false => quotes.iter().map(|q| quote_line(q, tz)).collect(),
+            }
+        }
+        ("del", _) => match (
+            rest.trim_start_matches('#').parse::<i64>(),
+            bot.is_privileged(&ctx.nick).await,
+        ) {
+            (Err(_), _) => vec![usage()],
+            (Ok(id), true) => {
+                info!("Quote #{id} on {channel} deleted by {}", ctx.nick);
+                match db_del_quote(&db, channel, id).await? {
+                    true => vec![format!("Deleted quote #{id}.")],
+                    false => vec![format!("No quote #{id} here.")],
+                }
+            }
+            (Ok(_), false) => vec!["Only privileged users can delete quotes.".to_string()],
+        },
+        (_, Ok(id)) if rest.is_empty() => match db_get_quote(&db, channel, id).await? {
+            Some(q) => vec![quote_line(&q, tz)],
+            None => vec![format!("No quote #{id} here.")],
+        },
+        _ => vec![usage()],
+    };
+    for line in lines {
+        ctx.reply(&bot, &line).await?;
+    }
+    Ok(true)
+}
+
+fn quote_line(q: &DbQuote, tz: Tz) -> String {
+    let added = DateTime::from_timestamp(q.added, 0)
+        .map(|ts| ts.with_timezone(&tz).format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    format!("#{}: {} (added by {} on {added})", q.id, q.quote, q.nick)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_date_in_channel_timezone() {
+        let q = DbQuote {
+            id: 42,
+            channel: "#chan".to_string(),
+            // 2026-10-18 22:30 UTC
+            added: 1_792_362_600,
+            nick: "alice".to_string(),
+            quote: "<bob> it works on my machine".to_string(),
+        };
+        assert_eq!(
+            quote_line(&q, Tz::UTC),
+            "#42: <bob> it works on my machine (added by alice on 2026-10-18)"
+        );
+        assert_eq!(
+            quote_line(&q, "Europe/Helsinki".parse().unwrap()),
+            "#42: <bob> it works on my machine (added by alice on 2026-10-19)"
+        );
+    }
+}
+
+// EOF
@@ -0,0 +1,268 @@
+// plugins/remind.rs
+
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// The channels where the command works, it is always available in private
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RemindConfig {
+    // per nick
+    pub max_pending: i64,
+    // how far ahead a reminder can be set
+    pub max_days: i64,
+}
+
+impl Default for RemindConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: 10,
+            max_days: 365,
+        }
+    }
+}
+
+impl PluginOpts for RemindConfig {
+    const CMD: &'static str = "!remind";
+}
+
+// `!remind <when> <text>`, the reminders are kept in the database and sent from the tick
+pub struct RemindPlugin;
+
+impl BotPlugin for RemindPlugin {
+    fn name(&self) -> &str {
+        "remind"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let cmd_remind = bot.config.read().await.remind.cmd.clone();
+            if !cmd_remind.is_empty() {
+                bot.register_chanmsg(&cmd_remind, into_msg_handler(handle_cmd_remind))
+                    .await;
+                bot.register_privmsg_open(&cmd_remind, into_msg_handler(handle_cmd_remind))
+                    .await;
+            }
+            Ok(())
+        })
+    }
+
+    fn on_tick(&self, bot: Arc<IrcBot>) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(remind_deliver(bot))
+    }
+}
+
+async fn remind_deliver(bot: Arc<IrcBot>) -> anyhow::Result<()> {
+    let (db, cmd_remind) = {
+        let cfg = bot.config.read().await;
+        (cfg.db.clone(), cfg.remind.cmd.clone())
+    };
+    let Some(db) = db else {
+        return Ok(());
+    };
+    if cmd_remind.is_empty() {
+        return Ok(());
+    }
+
+    let channels = bot.state.read().await.channels.iter().cloned().collect::<Vec<_>>();
+    let now = Utc::now().timestamp();
+    for rem in db_take_due_reminders(&db, now, &channels).await? {
+        info!("Reminder #{} for {} is due", rem.id, rem.nick);
+        let ago = (now - rem.created).human_duration();
+        let (target, line) = match rem.channel.is_empty() {
+            true => (&rem.nick, format!("Reminder from {ago} ago: {}", rem.msg)),
+            false => (
+                &rem.channel,
+                format!("{}: reminder from {ago} ago: {}", rem.nick, rem.msg),
+            ),
+        };
+        bot.clone().new_msg(target, &line).await?;
+    }
+    Ok(())
+}
+
+async fn handle_cmd_remind(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let (db, tz, max_pending, max_days) = {
+        let cfg = bot.config.read().await;
+        if let Some(channel) = &ctx.channel
+            && !cfg.remind.enabled(channel)
+        {
+            return Ok(false);
+        }
+        (
+            cfg.db.clone(),
+            cfg.channel_tz(ctx.reply_target()),
+            cfg.remind.opts.max_pending,
+            cfg.remind.opts.max_days,
+        )
+    };
+    let Some(db) = db else {
+        bail!("No database pool for reminders");
+    };
+
+    let now = Utc::now();
+    let (due, msg) = match parse_remind(&ctx.args, tz, now) {
+        Ok(res) => res,
+        Err(e) => {
+            ctx.reply(&bot, &format!("{e}. Usage: {} <when> <text>", ctx.cmd))
+                .await?;
+            return Ok(true);
+        }
+    };
+    if due - now > TimeDelta::days(max_days) {
+        ctx.reply(&bot, &format!("That is more than {max_days} days away."))
+            .await?;
+        return Ok(true);
+    }
+
+    let rem = DbReminder {
+        id: 0,
+        created: now.timestamp(),
+        due: due.timestamp(),
+        channel: ctx.channel.clone().unwrap_or_default(),
+        nick: ctx.nick.clone(),
+        msg,
+    };
+    let reply = match db_add_reminder(&db, &rem, max_pending).await? {
+        Some(id) => {
+            info!("Reminder #{id} for {} at {due}", ctx.nick);
+            format!(
+                "OK, I will remind you at {} (#{id}).",
+                due.with_timezone(&tz).format("%Y-%m-%d %H:%M %Z")
+            )
+        }
+        None => format!("You already have {max_pending} reminders waiting."),
+    };
+    ctx.reply(&bot, &reply).await?;
+    Ok(true)
+}
+
+// `in 2h30m`, `today 18:00`, `tomorrow 09:00`, `2026-11-01 18:00` or just `18:00` (the next
+// one) followed by the text. Clock times are in `tz`.
+fn parse_remind(args: &str, tz: Tz, now: DateTime<Utc>) -> anyhow::Result<(DateTime<Utc>, String)> {
+    let mut words = args.split_whitespace().peekable();
+    let today = now.with_timezone(&tz).date_naive();
+    let due = match words.next() {
+        None => bail!("When?"),
+        Some("in") => {
+            let mut secs = 0i64;
+            while let Some(s) = words.peek().and_then(|w| parse_duration(w)) {
+                secs = secs.saturating_add(s);
+                words.next();
+            }
+            if secs == 0 {
+                bail!("Give the time as e.g. 2h30m");
+            }
+            TimeDelta::try_seconds(secs)
+                .and_then(|d| now.checked_add_signed(d))
+                .ok_or_else(|| anyhow!("That is too far away"))?
+        }
+        Some("today") => local_time(tz, today, words.next())?,
+        Some("tomorrow") => local_time(tz, today + TimeDelta::days(1), words.next())?,
+        Some(word) => match NaiveDate::parse_from_str(word, "%Y-%m-%d") {
+            Ok(date) => local_time(tz, date, words.next())?,
+            Err(_) => {
+                let due = local_time(tz, today, Some(word))?;
+                match due > now {
+                    true => due,
+                    false => local_time(tz, today + TimeDelta::days(1), Some(word))?,
+                }
+            }
+        },
+    };
+    if due <= now {
+        bail!("That is in the past");
+    }
+
+    let text = words.collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        bail!("Remind of what?");
+    }
+    Ok((due, text))
+}
+
+fn local_time(tz: Tz, date: NaiveDate, time: Option<&str>) -> anyhow::Result<DateTime<Utc>> {
+    let Some(time) = time.and_then(|t| NaiveTime::parse_from_str(t, "%H:%M").ok()) else {
+        bail!("Give the time as HH:MM");
+    };
+    let local = date.and_time(time);
+    match tz.from_local_datetime(&local).earliest() {
+        Some(t) => Ok(t.with_timezone(&Utc)),
+        // skipped by a DST change
+        None => bail!("{local} does not exist in {tz}"),
+    }
+}
+
+// e.g. "2h30m" or "1w" in seconds, None if it is not a duration
+fn parse_duration(word: &str) -> Option<i64> {
+    let mut secs = 0i64;
+    let mut num = String::new();
+    for c in word.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let unit = match c {
+            'w' => 604800,
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        secs = secs.checked_add(num.parse::<i64>().ok()?.checked_mul(unit)?)?;
+        num.clear();
+    }
+    // a trailing number without a unit is not a duration
+    (num.is_empty() && secs > 0).then_some(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("2h30m"), Some(9000));
+        assert_eq!(parse_duration("1w1s"), Some(604801));
+        assert_eq!(parse_duration("90"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("tea"), None);
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+    }
+
+    #[test]
+    fn relative_and_absolute_times() {
+        let tz: Tz = "Europe/Helsinki".parse().unwrap();
+        // 2026-10-18 12:00 in Helsinki (EEST)
+        let now = DateTime::parse_from_rfc3339("2026-10-18T09:00:00Z").unwrap().to_utc();
+        let parse =
+            |args: &str| parse_remind(args, tz, now).map(|(due, text)| (due.with_timezone(&tz).to_rfc3339(), text));
+
+        assert_eq!(
+            parse("in 2h30m  check  the oven").unwrap(),
+            ("2026-10-18T14:30:00+03:00".to_string(), "check the oven".to_string())
+        );
+        assert_eq!(parse("in 1d 2h tea").unwrap().0, "2026-10-19T14:00:00+03:00");
+        assert_eq!(parse("tomorrow 09:00 standup").unwrap().0, "2026-10-19T09:00:00+03:00");
+        assert_eq!(parse("today 18:00 dinner").unwrap().0, "2026-10-18T18:00:00+03:00");
+        // after the DST change
+        assert_eq!(parse("2026-11-01 18:00 vote").unwrap().0, "2026-11-01T18:00:00+02:00");
+        // the next 11:00 is tomorrow
+        assert_eq!(parse("11:00 coffee").unwrap().0, "2026-10-19T11:00:00+03:00");
+        assert_eq!(parse("13:00 lunch").unwrap().0, "2026-10-18T13:00:00+03:00");
+
+        assert!(parse("today 11:00 too late").is_err());
+        assert!(parse("in 2h").is_err());
+        assert!(parse("in soon tea").is_err());
+        assert!(parse("tomorrow tea").is_err());
+        assert!(parse("2027-03-28 03:30 skipped by DST").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("in 9999999999999w tea").is_err());
+    }
+}
+
+// EOF
@@ -0,0 +1,322 @@
+// plugins/script.rs
+
+// Rhai scripts for small custom commands and triggers, listed under `scripts` in the bot config.
+//
+// A script sees the sender and message as scope variables: nick, user, host, userhost, account,
+// channel, target, msg, cmd, args (string) and argv (array). Triggers also get `captures`,
+// the capture groups of the trigger regex, the triggers are tried in script name order. Functions available to scripts:
+//   reply(text)              reply to the channel or nick the message came from, at most
+//                            SCRIPT_MAX_REPLIES times per run
+//   get_text_body(url)       body of a text/* url, or () on any failure
+//   random(lo, hi)           random integer in lo..=hi
+
+use std::{collections::BTreeMap, fs, sync::Mutex, time::Instant};
+
+use futures::future::BoxFuture;
+use rhai::{AST, Array, Dynamic, Engine, Scope};
+
+use crate::*;
+
+const SCRIPT_MAX_OPERATIONS: u64 = 1_000_000;
+const SCRIPT_MAX_STRING: usize = 64 * 1024;
+const SCRIPT_MAX_REPLIES: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScriptCmd {
+    // path to the script, $HOME is expanded
+    pub file: String,
+    // command word, e.g. "!dice"
+    #[serde(default)]
+    pub command: String,
+    // register the command for channel messages
+    #[serde(default)]
+    pub channel: bool,
+    // register the command for private messages
+    #[serde(default)]
+    pub privmsg: bool,
+    // run for channel messages matching this regex, if not empty
+    #[serde(default)]
+    pub trigger: String,
+    // in milliseconds
+    #[serde(default = "default_script_timeout")]
+    pub timeout: u64,
+}
+
+fn default_script_timeout() -> u64 {
+    1000
+}
+
+struct LoadedScript {
+    name: String,
+    cfg: ScriptCmd,
+    ast: AST,
+    trigger_re: Option<Regex>,
+}
+
+#[derive(Default)]
+struct Scripts {
+    scripts: RwLock<Arc<BTreeMap<String, Arc<LoadedScript>>>>,
+    // the commands registered by the last load, as (command, channel, privmsg)
+    commands: RwLock<Vec<(String, bool, bool)>>,
+}
+
+#[derive(Default)]
+pub struct ScriptPlugin {
+    inner: Arc<Scripts>,
+}
+
+impl ScriptPlugin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    async fn load(&self, bot: &Arc<IrcBot>) -> anyhow::Result<()> {
+        let config = bot.config.read().await.scripts.clone();
+        let engine = Engine::new();
+        let mut scripts = BTreeMap::new();
+
+        for (name, cfg) in config {
+            let file = shellexpand::full(&cfg.file)?.into_owned();
+            let ast = match fs::read_to_string(&file)
+                .map_err(anyhow::Error::from)
+                .and_then(|src| engine.compile(src).map_err(|e| anyhow!("{e}")))
+            {
+                Ok(ast) => ast,
+                Err(e) => {
+                    error!("Could not load script {name} from {file}: {e}");
+                    continue;
+                }
+            };
+            let trigger_re = match cfg.trigger.as_str() {
+                "" => None,
+                t => match Regex::new(t) {
+                    Ok(re) => Some(re),
+                    Err(e) => {
+                        error!("Bad trigger for script {name}: {e}");
+                        continue;
+                    }
+                },
+            };
+            info!("Loaded script {name}");
+            let script = LoadedScript {
+                name: name.clone(),
+                cfg,
+                ast,
+                trigger_re,
+            };
+            scripts.insert(name, Arc::new(script));
+        }
+
+        // the scripts that were removed or renamed take their commands along
+        for (cmd, channel, privmsg) in self.inner.commands.write().await.drain(..) {
+            if channel {
+                bot.unregister_chanmsg(&cmd).await;
+            }
+            if privmsg {
+                bot.unregister_privmsg_open(&cmd).await;
+            }
+        }
+
+        // (re)register the commands, the handlers look the script up by name on every call
+        let mut commands = Vec::new();
+        for (name, script) in &scripts {
+            if script.cfg.command.is_empty() {
+                continue;
+            }
+            let handler = |name: String, inner: Arc<Scripts>| {
+                into_msg_handler(move |bot, ctx| run_script_cmd(inner.clone(), name.clone(), bot, ctx))
+            };
+            if script.cfg.channel {
+                bot.register_chanmsg(&script.cfg.command, handler(name.clone(), self.inner.clone()))
+                    .await;
+            }
+            if script.cfg.privmsg {
+                bot.register_privmsg_open(&script.cfg.command, handler(name.clone(), self.inner.clone()))
+                    .await;
+            }
+            commands.push((script.cfg.command.clone(), script.cfg.channel, script.cfg.privmsg));
+        }
+
+        *self.inner.commands.write().await = commands;
+        *self.inner.scripts.write().await = Arc::new(scripts);
+        Ok(())
+    }
+
+    async fn handle_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+        if ctx.channel.is_none() {
+            return Ok(false);
+        }
+        let scripts = self.inner.scripts.read().await.clone();
+        for script in scripts.values() {
+            let Some(captures) = script.trigger_re.as_ref().and_then(|re| re.captures(&ctx.msg)) else {
+                continue;
+            };
+            let captures = captures
+                .iter()
+                .map(|c| c.map_or(Dynamic::UNIT, |m| m.as_str().into()))
+                .collect::<Array>();
+            if run_script(&bot, script.clone(), &ctx, Some(captures)).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl BotPlugin for ScriptPlugin {
+    fn name(&self) -> &str {
+        "script"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.load(bot))
+    }
+
+    fn on_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(self.handle_message(bot, ctx))
+    }
+
+    fn on_reload(&self, bot: Arc<IrcBot>) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move { self.load(&bot).await })
+    }
+}
+
+async fn run_script_cmd(inner: Arc<Scripts>, name: String, bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let script = inner.scripts.read().await.get(&name).cloned();
+    match script {
+        Some(script) => run_script(&bot, script, &ctx, None).await,
+        // removed by a reload
+        None => Ok(false),
+    }
+}
+
+async fn run_script(
+    bot: &Arc<IrcBot>,
+    script: Arc<LoadedScript>,
+    ctx: &MsgContext,
+    captures: Option<Array>,
+) -> anyhow::Result<bool> {
+    let timeout = Duration::from_millis(script.cfg.timeout);
+    let job_ctx = ctx.clone();
+    let name = script.name.clone();
+    let job = tokio::task::spawn_blocking(move || eval_script(&script.ast, &job_ctx, captures, timeout));
+
+    let replies = match job.await? {
+        Ok(replies) => replies,
+        Err(e) => {
+            error!("Script {name} failed: {e}");
+            return Ok(false);
+        }
+    };
+    for reply in &replies {
+        ctx.reply(bot, reply).await?;
+    }
+    Ok(true)
+}
+
+// Runs the script to completion, or until it runs out of time, and returns its replies
+fn eval_script(ast: &AST, ctx: &MsgContext, captures: Option<Array>, timeout: Duration) -> anyhow::Result<Vec<String>> {
+    let replies = Arc::new(Mutex::new(Vec::new()));
+    let deadline = Instant::now() + timeout;
+
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(SCRIPT_MAX_OPERATIONS)
+        .set_max_string_size(SCRIPT_MAX_STRING)
+        .on_progress(move |_| (Instant::now() > deadline).then(|| "time limit exceeded".into()));
+
+    let r = replies.clone();
+    engine.register_fn("reply", move |text: &str| -> Result<(), Box<rhai::EvalAltResult>> {
+        if let Ok(mut replies) = r.lock() {
+            if replies.len() >= SCRIPT_MAX_REPLIES {
+                return Err("too many replies".into());
+            }
+            replies.push(text.to_string());
+        }
+        Ok(())
+    });
+    engine.register_fn("get_text_body", move |url: &str| -> Dynamic {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // we are on a blocking thread of the runtime, see run_script()
+        let body = tokio::runtime::Handle::current()
+            .block_on(async { tokio::time::timeout(remaining, get_text_body(url)).await });
+        match body {
+            Ok(Ok(Some((body, _ct)))) => body.into(),
+            _ => Dynamic::UNIT,
+        }
+    });
+    engine.register_fn("random", |lo: i64, hi: i64| -> i64 {
+        if lo >= hi { lo } else { rand::random_range(lo..=hi) }
+    });
+
+    let mut scope = Scope::new();
+    scope
+        .push_constant("nick", ctx.nick.clone())
+        .push_constant("user", ctx.user.clone())
+        .push_constant("host", ctx.host.clone())
+        .push_constant("userhost", ctx.userhost.clone())
+        .push_constant("account", ctx.account.clone().unwrap_or_default())
+        .push_constant("channel", ctx.channel.clone().unwrap_or_default())
+        .push_constant("target", ctx.target.clone())
+        .push_constant("msg", ctx.msg.clone())
+        .push_constant("cmd", ctx.cmd.clone())
+        .push_constant("args", ctx.args.clone())
+        .push_constant(
+            "argv",
+            ctx.args
+                .split_whitespace()
+                .map(|a| Dynamic::from(a.to_string()))
+                .collect::<Array>(),
+        );
+    if let Some(captures) = captures {
+        scope.push_constant("captures", captures);
+    }
+
+    engine.run_ast_with_scope(&mut scope, ast).map_err(|e| anyhow!("{e}"))?;
+
+    let replies = replies.lock().map_err(|e| anyhow!("{e}"))?.clone();
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str, timeout: u64) -> anyhow::Result<Vec<String>> {
+        let ast = Engine::new().compile(src).expect("test script should compile");
+        let message: Message = ":alice!ali@example.com PRIVMSG #test :!roll 2 6"
+            .parse()
+            .expect("privmsg should parse");
+        let ctx = MsgContext::new(&message, "sjmbot");
+        eval_script(&ast, &ctx, None, Duration::from_millis(timeout))
+    }
+
+    #[test]
+    fn script_sees_sender_and_can_reply() {
+        let replies = eval(
+            r#"
+                let n = parse_int(argv[0]);
+                let d = random(1, parse_int(argv[1]));
+                reply(`${nick} on ${channel}: ${n}d, got ${d}`);
+            "#,
+            1000,
+        )
+        .expect("test script should run");
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].starts_with("alice on #test: 2d, got "));
+    }
+
+    #[test]
+    fn script_is_stopped_at_time_limit() {
+        assert!(eval("loop { }", 50).is_err());
+    }
+
+    #[test]
+    fn script_replies_are_capped() {
+        let enough = format!("for i in 0..{SCRIPT_MAX_REPLIES} {{ reply(`${{i}}`); }}");
+        assert_eq!(eval(&enough, 1000).unwrap().len(), SCRIPT_MAX_REPLIES);
+        assert!(eval("loop { reply(\"spam\"); }", 1000).is_err());
+    }
+}
+
+// EOF
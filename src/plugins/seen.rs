@@ -0,0 +1,251 @@
+// plugins/seen.rs
+
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// The channels where the last action of each nick is recorded, the command works in channel
+// and private messages
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SeenConfig {}
+
+impl PluginOpts for SeenConfig {
+    const CMD: &'static str = "!seen";
+}
+
+// rows waiting for the database writer
+const SEEN_QUEUE_CAPACITY: usize = 256;
+
+// Records the last action of every nick on a channel and answers `!seen <nick>`
+pub struct SeenPlugin {
+    queue: mpsc::Sender<(DbCtx, DbSeen)>,
+}
+
+impl SeenPlugin {
+    // Starts the database writer, the channel workers only queue the rows for it
+    pub fn new() -> Self {
+        let (queue, mut rx) = mpsc::channel::<(DbCtx, DbSeen)>(SEEN_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some((db, row)) = rx.recv().await {
+                if let Err(e) = db_upsert_seen(&db, &row).await {
+                    error!("Seen update failed for {} on {}: {e:#}", row.nick, row.channel);
+                }
+            }
+        });
+        Self { queue }
+    }
+}
+
+impl Default for SeenPlugin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BotPlugin for SeenPlugin {
+    fn name(&self) -> &str {
+        "seen"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let cmd_seen = bot.config.read().await.seen.cmd.clone();
+            if !cmd_seen.is_empty() {
+                bot.register_chanmsg(&cmd_seen, into_msg_handler(handle_cmd_seen)).await;
+                bot.register_privmsg_open(&cmd_seen, into_msg_handler(handle_cmd_seen))
+                    .await;
+            }
+            Ok(())
+        })
+    }
+
+    fn on_event(&self, bot: Arc<IrcBot>, event: LogEvent) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(handle_seen_store(bot, event, self.queue.clone()))
+    }
+}
+
+async fn handle_seen_store(
+    bot: Arc<IrcBot>,
+    event: LogEvent,
+    queue: mpsc::Sender<(DbCtx, DbSeen)>,
+) -> anyhow::Result<()> {
+    if !is_channel_name(&event.target) {
+        return Ok(());
+    }
+    let rows = seen_rows(&event);
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let db = {
+        let cfg = bot.config.read().await;
+        if !cfg.seen.enabled(&event.target) {
+            return Ok(());
+        }
+        cfg.db.clone().ok_or_else(|| anyhow!("No database pool for seen"))?
+    };
+
+    // the channel worker does not wait for the database, a slow one loses updates instead
+    for row in rows {
+        if let Err(e) = queue.try_send((db.clone(), row)) {
+            metrics().inc("sjmb_queue_dropped_total", &[("queue", "seen")]);
+            warn!("Seen update dropped on {}: {e}", event.target);
+        }
+    }
+    Ok(())
+}
+
+// Kicks and nick changes are about two nicks
+fn seen_rows(event: &LogEvent) -> Vec<DbSeen> {
+    let row = |nick: &str, action: &str, other: &str, msg: &str| DbSeen {
+        channel: event.target.clone(),
+        nick: nick.to_string(),
+        seen: event.ts.timestamp(),
+        action: action.to_string(),
+        other: other.to_string(),
+        msg: msg.to_string(),
+    };
+    let LogEvent { nick, text, .. } = event;
+    match &event.kind {
+        LogKind::Message => vec![row(nick, "message", "", text)],
+        LogKind::Action => vec![row(nick, "message", "", &format!("/me {text}"))],
+        LogKind::Join => vec![row(nick, "join", "", "")],
+        LogKind::Part => vec![row(nick, "part", "", text)],
+        LogKind::Quit => vec![row(nick, "quit", "", text)],
+        LogKind::Topic => vec![row(nick, "topic", "", text)],
+        LogKind::Kick(victim) => vec![row(nick, "kick", victim, text), row(victim, "kicked", nick, text)],
+        LogKind::Nick(new_nick) => vec![row(nick, "nick", new_nick, ""), row(new_nick, "nick_from", nick, "")],
+        LogKind::Notice | LogKind::Mode => Vec::new(),
+    }
+}
+
+fn seen_what(row: &DbSeen) -> String {
+    let reason = match row.msg.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", row.msg),
+    };
+    match row.action.as_str() {
+        "message" => format!("saying: {}", row.msg),
+        "join" => "joining".to_string(),
+        "part" => format!("leaving{reason}"),
+        "quit" => format!("quitting{reason}"),
+        "topic" => format!("changing the topic to: {}", row.msg),
+        "kick" => format!("kicking {}{reason}", row.other),
+        "kicked" => format!("being kicked by {}{reason}", row.other),
+        "nick" => format!("changing nick to {}", row.other),
+        "nick_from" => format!("changing nick from {}", row.other),
+        action => action.to_string(),
+    }
+}
+
+fn seen_line(row: &DbSeen, tz: Tz, now: i64) -> String {
+    let when = DateTime::from_timestamp(row.seen, 0)
+        .map(|ts| ts.with_timezone(&tz).format("%Y-%m-%d %H:%M %Z").to_string())
+        .unwrap_or_default();
+    format!(
+        "{} was last seen on {} at {when} ({} ago), {}",
+        row.nick,
+        row.channel,
+        (now - row.seen).human_duration(),
+        seen_what(row)
+    )
+}
+
+// In a channel only that channel is looked at, in private all the recorded channels the
+// requester is on
+async fn handle_cmd_seen(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(nick) = ctx.args.split_whitespace().next() else {
+        ctx.reply(&bot, &format!("Usage: {} <nick>", ctx.cmd)).await?;
+        return Ok(true);
+    };
+
+    let candidates = match &ctx.channel {
+        Some(channel) => vec![channel.clone()],
+        None => bot.channels_of(&ctx.nick).await,
+    };
+    let (db, channels) = {
+        let cfg = bot.config.read().await;
+        let channels = candidates
+            .into_iter()
+            .filter(|c| cfg.seen.enabled(c))
+            .collect::<Vec<_>>();
+        (cfg.db.clone(), channels)
+    };
+    let Some(db) = db else {
+        bail!("No database pool for seen");
+    };
+    if channels.is_empty() {
+        ctx.reply(&bot, "Nobody is being watched here.").await?;
+        return Ok(true);
+    }
+
+    info!("Seen query by {} in {channels:?}: {nick}", ctx.nick);
+    let reply = match db_get_seen(&db, &channels, nick).await? {
+        Some(row) => {
+            let tz = bot.config.read().await.channel_tz(&row.channel);
+            seen_line(&row, tz, Utc::now().timestamp())
+        }
+        None => format!("I have not seen {nick}."),
+    };
+    ctx.reply(&bot, &reply).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nick_change_and_kick_record_both_nicks() {
+        let event = |kind: LogKind, text: &str| LogEvent {
+            ts: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            target: "#chan".to_string(),
+            kind,
+            nick: "alice".to_string(),
+            userhost: "a@example.net".to_string(),
+            account: None,
+            tags: Vec::new(),
+            text: text.to_string(),
+        };
+        let lines = |rows: Vec<DbSeen>| {
+            rows.iter()
+                .map(|r| format!("{}: {}", r.nick, seen_what(r)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            lines(seen_rows(&event(LogKind::Nick("bob".to_string()), ""))),
+            ["alice: changing nick to bob", "bob: changing nick from alice"]
+        );
+        assert_eq!(
+            lines(seen_rows(&event(LogKind::Kick("bob".to_string()), "spam"))),
+            ["alice: kicking bob (spam)", "bob: being kicked by alice (spam)"]
+        );
+        assert_eq!(
+            lines(seen_rows(&event(LogKind::Action, "waves"))),
+            ["alice: saying: /me waves"]
+        );
+        assert_eq!(lines(seen_rows(&event(LogKind::Quit, ""))), ["alice: quitting"]);
+        assert!(seen_rows(&event(LogKind::Mode, "+o bob")).is_empty());
+    }
+
+    #[test]
+    fn seen_line_in_channel_timezone() {
+        let row = DbSeen {
+            channel: "#chan".to_string(),
+            nick: "Alice".to_string(),
+            seen: 1_700_000_000,
+            action: "part".to_string(),
+            other: String::new(),
+            msg: "bye".to_string(),
+        };
+        assert_eq!(
+            seen_line(&row, "Europe/Helsinki".parse().unwrap(), 1_700_000_000 + 3723),
+            "Alice was last seen on #chan at 2023-11-15 00:13 EET (1h 2m 3s ago), leaving (bye)"
+        );
+    }
+}
+
+// EOF
@@ -0,0 +1,309 @@
+// plugins/tell.rs
+
+use std::collections::HashSet;
+
+use futures::future::BoxFuture;
+use tokio::sync::Mutex;
+
+use crate::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TellDelivery {
+    // a private message to the recipient
+    #[default]
+    Privmsg,
+    // on the channel where the recipient showed up, messages left in private still go privately
+    Channel,
+}
+
+// The channels where !tell works and messages are delivered, the commands work in channel
+// and private messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TellConfig {
+    pub delivery: HashMap<String, TellDelivery>,
+    // listing and cancelling, empty disables the command
+    pub cmd_tells: String,
+    // per sender
+    pub max_pending: i64,
+}
+
+impl Default for TellConfig {
+    fn default() -> Self {
+        Self {
+            delivery: HashMap::new(),
+            cmd_tells: "!tells".to_string(),
+            max_pending: 5,
+        }
+    }
+}
+
+impl PluginOpts for TellConfig {
+    const CMD: &'static str = "!tell";
+}
+
+// Leaves messages with `!tell <nick> <message>`, delivered when the nick next joins or
+// speaks. `!tells` lists one's own pending messages and `!tells cancel <id>` takes one back.
+#[derive(Default)]
+pub struct TellPlugin {
+    // lowercased recipients with messages waiting, None until read from the database.
+    // Checked before the database on every channel line, a cancelled message may leave a stale entry.
+    pending: Arc<Mutex<Option<HashSet<String>>>>,
+}
+
+impl TellPlugin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BotPlugin for TellPlugin {
+    fn name(&self) -> &str {
+        "tell"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let (cmd_tell, cmd_tells) = {
+                let cfg = bot.config.read().await;
+                (cfg.tell.cmd.clone(), cfg.tell.opts.cmd_tells.clone())
+            };
+            if !cmd_tell.is_empty() {
+                let handler = |pending: Arc<Mutex<Option<HashSet<String>>>>| {
+                    into_msg_handler(move |bot, ctx| handle_cmd_tell(bot, ctx, pending.clone()))
+                };
+                bot.register_chanmsg(&cmd_tell, handler(self.pending.clone())).await;
+                bot.register_privmsg_open(&cmd_tell, handler(self.pending.clone()))
+                    .await;
+            }
+            if !cmd_tells.is_empty() {
+                bot.register_chanmsg(&cmd_tells, into_msg_handler(handle_cmd_tells))
+                    .await;
+                bot.register_privmsg_open(&cmd_tells, into_msg_handler(handle_cmd_tells))
+                    .await;
+            }
+            Ok(())
+        })
+    }
+
+    fn on_event(&self, bot: Arc<IrcBot>, event: LogEvent) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(handle_tell_deliver(bot, event, self.pending.clone()))
+    }
+}
+
+async fn handle_tell_deliver(
+    bot: Arc<IrcBot>,
+    event: LogEvent,
+    pending: Arc<Mutex<Option<HashSet<String>>>>,
+) -> anyhow::Result<()> {
+    if !matches!(event.kind, LogKind::Join | LogKind::Message | LogKind::Action) || !is_channel_name(&event.target) {
+        return Ok(());
+    }
+    let (db, delivery) = {
+        let cfg = bot.config.read().await;
+        if !cfg.tell.enabled(&event.target) {
+            return Ok(());
+        }
+        let delivery = get_wild(&cfg.tell.opts.delivery, &event.target)
+            .copied()
+            .unwrap_or_default();
+        (
+            cfg.db.clone().ok_or_else(|| anyhow!("No database pool for tell"))?,
+            delivery,
+        )
+    };
+
+    // taking the nick out claims the delivery, a parallel event for the same nick finds nothing to do
+    let key = event.nick.to_lowercase();
+    {
+        let mut pending = pending.lock().await;
+        let mut recipients = match pending.take() {
+            Some(recipients) => recipients,
+            None => db_tell_recipients(&db).await?,
+        };
+        let claimed = recipients.remove(&key);
+        *pending = Some(recipients);
+        if !claimed {
+            return Ok(());
+        }
+    }
+
+    // the undelivered messages stay in the database, put the nick back to retry them later
+    if let Err(e) = deliver_tells(&bot, &db, &event, delivery).await {
+        if let Some(recipients) = pending.lock().await.as_mut() {
+            recipients.insert(key);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn deliver_tells(bot: &Arc<IrcBot>, db: &DbCtx, event: &LogEvent, delivery: TellDelivery) -> anyhow::Result<()> {
+    let tells = db_get_tells(db, &event.nick).await?;
+    if tells.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Delivering {} messages to {} on {}",
+        tells.len(),
+        event.nick,
+        event.target
+    );
+    let now = Utc::now().timestamp();
+    for tell in tells {
+        let line = tell_line(&tell, now);
+        match delivery {
+            TellDelivery::Channel if !tell.channel.is_empty() => {
+                bot.clone()
+                    .new_msg(&event.target, &format!("{}: {line}", event.nick))
+                    .await?;
+            }
+            _ => {
+                bot.clone().new_msg(&event.nick, &line).await?;
+            }
+        }
+        db_delete_tell(db, tell.id).await?;
+    }
+    Ok(())
+}
+
+fn tell_line(tell: &DbTell, now: i64) -> String {
+    let place = match tell.channel.is_empty() {
+        true => "in private".to_string(),
+        false => format!("on {}", tell.channel),
+    };
+    format!(
+        "{} left you a message {place} {} ago: {}",
+        tell.sender,
+        (now - tell.created).human_duration(),
+        tell.msg
+    )
+}
+
+// The recipient and the message
+fn parse_tell(args: &str) -> Option<(&str, &str)> {
+    let (nick, msg) = args.trim().split_once(char::is_whitespace)?;
+    let msg = msg.trim();
+    (!msg.is_empty()).then_some((nick, msg))
+}
+
+async fn handle_cmd_tell(
+    bot: Arc<IrcBot>,
+    ctx: MsgContext,
+    pending: Arc<Mutex<Option<HashSet<String>>>>,
+) -> anyhow::Result<bool> {
+    let (db, max_pending) = {
+        let cfg = bot.config.read().await;
+        if let Some(channel) = &ctx.channel
+            && !cfg.tell.enabled(channel)
+        {
+            return Ok(false);
+        }
+        (cfg.db.clone(), cfg.tell.opts.max_pending)
+    };
+    let Some(db) = db else {
+        bail!("No database pool for tell");
+    };
+
+    let Some((nick, msg)) = parse_tell(&ctx.args) else {
+        ctx.reply(&bot, &format!("Usage: {} <nick> <message>", ctx.cmd)).await?;
+        return Ok(true);
+    };
+    if nick.eq_ignore_ascii_case(&ctx.nick) || nick.eq_ignore_ascii_case(&ctx.my_nick) {
+        ctx.reply(&bot, "Tell that yourself.").await?;
+        return Ok(true);
+    }
+
+    let tell = DbTell {
+        id: 0,
+        created: Utc::now().timestamp(),
+        channel: ctx.channel.clone().unwrap_or_default(),
+        sender: ctx.nick.clone(),
+        recipient: nick.to_string(),
+        msg: msg.to_string(),
+    };
+    let reply = match db_add_tell(&db, &tell, max_pending).await? {
+        Some(id) => {
+            info!("Message #{id} from {} to {nick}", ctx.nick);
+            if let Some(recipients) = pending.lock().await.as_mut() {
+                recipients.insert(nick.to_lowercase());
+            }
+            format!("OK, I will pass that on to {nick} (#{id}).")
+        }
+        None => format!("You already have {max_pending} messages waiting."),
+    };
+    ctx.reply(&bot, &reply).await?;
+    Ok(true)
+}
+
+// The list goes privately, it is nobody else's business
+async fn handle_cmd_tells(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(db) = bot.config.read().await.db.clone() else {
+        bail!("No database pool for tell");
+    };
+
+    let mut args = ctx.args.split_whitespace();
+    match (args.next(), args.next().map(str::parse::<i64>)) {
+        (None, _) => {
+            let tells = db_list_tells(&db, &ctx.nick).await?;
+            if tells.is_empty() {
+                ctx.reply(&bot, "You have no messages waiting.").await?;
+            }
+            let now = Utc::now().timestamp();
+            for tell in tells {
+                let line = format!(
+                    "#{} to {}, {} ago: {}",
+                    tell.id,
+                    tell.recipient,
+                    (now - tell.created).human_duration(),
+                    tell.msg
+                );
+                bot.clone().new_msg(&ctx.nick, &line).await?;
+            }
+        }
+        (Some("cancel"), Some(Ok(id))) => {
+            let reply = match db_cancel_tell(&db, &ctx.nick, id).await? {
+                true => format!("Cancelled #{id}."),
+                false => format!("You have no message #{id} waiting."),
+            };
+            ctx.reply(&bot, &reply).await?;
+        }
+        _ => {
+            ctx.reply(&bot, &format!("Usage: {} [cancel <id>]", ctx.cmd)).await?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tell_args_and_delivery_line() {
+        assert_eq!(parse_tell(" bob  see you at 6 "), Some(("bob", "see you at 6")));
+        assert_eq!(parse_tell("bob"), None);
+        assert_eq!(parse_tell("bob   "), None);
+
+        let mut tell = DbTell {
+            id: 1,
+            created: 1_700_000_000,
+            channel: "#chan".to_string(),
+            sender: "alice".to_string(),
+            recipient: "bob".to_string(),
+            msg: "see you at 6".to_string(),
+        };
+        assert_eq!(
+            tell_line(&tell, 1_700_000_000 + 7260),
+            "alice left you a message on #chan 2h 1m 0s ago: see you at 6"
+        );
+        tell.channel.clear();
+        assert_eq!(
+            tell_line(&tell, 1_700_000_000 + 5),
+            "alice left you a message in private 5s ago: see you at 6"
+        );
+    }
+}
+
+// EOF
@@ -0,0 +1,65 @@
+// plugins/url_cmd.rs
+
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// Template-based `!cmd args` commands that fetch an URL and say what the output filter matches
+pub struct UrlCmdPlugin;
+
+impl BotPlugin for UrlCmdPlugin {
+    fn name(&self) -> &str {
+        "url_cmd"
+    }
+
+    fn on_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(handle_url_cmd(bot, ctx))
+    }
+}
+
+async fn handle_url_cmd(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(channel) = ctx.channel else {
+        return Ok(false);
+    };
+    // url_cmd starts with '!'
+    let Some(u_cmd) = ctx.cmd.strip_prefix('!') else {
+        return Ok(false);
+    };
+
+    let job = {
+        let cfg = bot.config.read().await;
+        let Some(true) = get_wild(&cfg.url_cmd_channels, &channel) else {
+            return Ok(false);
+        };
+        let Some(c) = cfg.url_cmd_list.get(u_cmd) else {
+            return Ok(false);
+        };
+
+        let args = &ctx.args;
+        let u_args = args.split_whitespace().collect::<Vec<&str>>();
+        debug!("Url cmd ctx arg: {args:?}");
+        debug!("Url cmd ctx args: {u_args:?}");
+
+        let mut tctx = tera::Context::new();
+        tctx.insert("arg", args);
+        tctx.insert("args", &u_args);
+        debug!("Url cmd ctx: {tctx:#?}");
+
+        let url = cfg
+            .url_cmd_tera
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No tera"))?
+            .render(u_cmd, &tctx)?;
+        info!("URL cmd: !{u_cmd} --> {url}");
+        let f = c
+            .output_filter_re
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No output regex"))?
+            .clone();
+        UrlJob::Fetch(url, channel, f)
+    };
+
+    bot.new_url_job(job).await
+}
+
+// EOF
@@ -0,0 +1,168 @@
+// plugins/url_log.rs
+
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// The channels where the URL log can be listed, the command works in channel messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UrlListConfig {
+    // lines of output at most
+    pub max_results: i64,
+}
+
+impl Default for UrlListConfig {
+    fn default() -> Self {
+        Self { max_results: 5 }
+    }
+}
+
+impl PluginOpts for UrlListConfig {
+    const CMD: &'static str = "!url";
+}
+
+// Logs channel URLs into the database and complains about duplicates. `!url last [n]`,
+// `!url search <term>`, `!url by <nick>` and `!url top` list the log of the channel.
+pub struct UrlLogPlugin;
+
+impl BotPlugin for UrlLogPlugin {
+    fn name(&self) -> &str {
+        "url_log"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let cmd_url = bot.config.read().await.url_list.cmd.clone();
+            if !cmd_url.is_empty() {
+                bot.register_chanmsg(&cmd_url, into_msg_handler(handle_cmd_url)).await;
+            }
+            Ok(())
+        })
+    }
+
+    fn on_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(handle_url_log(bot, ctx))
+    }
+}
+
+async fn handle_url_log(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(channel) = ctx.channel else {
+        return Ok(false);
+    };
+    if ctx.urls.is_empty() {
+        return Ok(false);
+    }
+
+    let jobs = {
+        let cfg = bot.config.read().await;
+        let Some(true) = get_wild(&cfg.url_log_channels, &channel) else {
+            return Ok(false);
+        };
+        let db = cfg
+            .db
+            .clone()
+            .ok_or_else(|| anyhow!("No database pool for URL logging"))?;
+
+        let dup_check = match get_wild(&cfg.url_dup_complain_channels, &channel) {
+            Some(true) => {
+                let expire_days = get_wild(&cfg.url_dup_expire_days, &channel).unwrap_or(&7);
+                Some((cfg.channel_tz(&channel), expire_days.to_owned()))
+            }
+            _ => None,
+        };
+
+        ctx.urls
+            .iter()
+            .map(|url_s| {
+                UrlJob::Log(UrlLogJob {
+                    db: db.clone(),
+                    url: url_s.clone(),
+                    channel: channel.to_owned(),
+                    nick: ctx.nick.to_owned(),
+                    ts: Utc::now().timestamp(),
+                    dup_check,
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for job in jobs {
+        bot.clone().new_url_job(job).await?;
+    }
+
+    Ok(false)
+}
+
+async fn handle_cmd_url(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(channel) = &ctx.channel else {
+        return Ok(false);
+    };
+    let (db, tz, max) = {
+        let cfg = bot.config.read().await;
+        if !cfg.url_list.enabled(channel) {
+            return Ok(false);
+        }
+        (cfg.db.clone(), cfg.channel_tz(channel), cfg.url_list.opts.max_results)
+    };
+    let Some(db) = db else {
+        bail!("No database pool for URL logging");
+    };
+
+    let args = ctx.args.trim();
+    let (sub, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let rest = rest.trim();
+    let query = match (sub, rest.parse::<i64>()) {
+        ("last", _) if rest.is_empty() => Some((UrlQuery::Last, max)),
+        ("last", Ok(n)) if n > 0 => Some((UrlQuery::Last, n.min(max))),
+        ("search", _) if !rest.is_empty() => Some((UrlQuery::Search(rest), max)),
+        ("by", _) if !rest.is_empty() && !rest.contains(char::is_whitespace) => Some((UrlQuery::By(rest), max)),
+        _ => None,
+    };
+
+    let lines = match (sub, query) {
+        (_, Some((query, limit))) => db_list_urls(&db, channel, query, limit)
+            .await?
+            .iter()
+            .map(|u| format!("[{}] {}: {}", local_ts(u.seen, tz), u.nick, u.url))
+            .collect::<Vec<_>>(),
+        ("top", None) if rest.is_empty() => db_top_urls(&db, channel, max)
+            .await?
+            .iter()
+            .map(|u| {
+                format!(
+                    "{} ({} times, first by {} at {})",
+                    u.url,
+                    u.cnt,
+                    u.nick,
+                    local_ts(u.first, tz)
+                )
+            })
+            .collect::<Vec<_>>(),
+        _ => {
+            ctx.reply(
+                &bot,
+                &format!("Usage: {} last [n] | search <term> | by <nick> | top", ctx.cmd),
+            )
+            .await?;
+            return Ok(true);
+        }
+    };
+
+    if lines.is_empty() {
+        ctx.reply(&bot, "No URLs found.").await?;
+    }
+    for line in lines {
+        ctx.reply(&bot, &line).await?;
+    }
+    Ok(true)
+}
+
+fn local_ts(ts: i64, tz: Tz) -> String {
+    DateTime::from_timestamp(ts, 0)
+        .map(|ts| ts.with_timezone(&tz).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+// EOF
@@ -0,0 +1,64 @@
+// plugins/url_title.rs
+
+use futures::future::BoxFuture;
+
+use crate::*;
+
+// Says the titles of channel URLs, and of their mutated versions when a mutation rule matches
+pub struct UrlTitlePlugin;
+
+impl BotPlugin for UrlTitlePlugin {
+    fn name(&self) -> &str {
+        "url_title"
+    }
+
+    fn on_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(handle_url_title(bot, ctx))
+    }
+}
+
+async fn handle_url_title(bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+    let Some(channel) = ctx.channel else {
+        return Ok(false);
+    };
+    if ctx.urls.is_empty() {
+        return Ok(false);
+    }
+
+    let (jobs, msgs) = {
+        let cfg = bot.config.read().await;
+        let mut jobs = Vec::new();
+        let mut msgs = Vec::new();
+
+        for url_s in &ctx.urls {
+            if let Some(true) = get_wild(&cfg.url_fetch_channels, &channel) {
+                jobs.push(UrlJob::Title(url_s.clone(), channel.to_owned()));
+            }
+
+            if let Some(true) = get_wild(&cfg.url_mut_channels, &channel)
+                && let Some((_i, new_url)) = cfg
+                    .url_mut_re
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("No url_mut_re"))?
+                    .re_mut(url_s)
+            {
+                debug!("Doing url mut");
+                msgs.push(new_url.clone());
+                jobs.push(UrlJob::Title(new_url, channel.to_string()));
+            }
+        }
+        (jobs, msgs)
+    };
+
+    let handled = !jobs.is_empty() || !msgs.is_empty();
+    for msg in msgs {
+        bot.clone().new_msg(&channel, &msg).await?;
+    }
+    for job in jobs {
+        bot.clone().new_url_job(job).await?;
+    }
+
+    Ok(handled)
+}
+
+// EOF
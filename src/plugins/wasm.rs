@@ -0,0 +1,618 @@
+// plugins/wasm.rs
+
+// Sandboxed WebAssembly plugins, loaded from `wasm_plugins.plugin_dir`.
+//
+// A module must export `memory`, `alloc(len: i32) -> i32` and `on_message(ptr: i32, len: i32) -> i32`.
+// For every message not handled by a command, the host allocates guest memory with `alloc`,
+// writes the message context there as JSON and calls `on_message`, which returns non-zero
+// if it handled the message. A fresh instance is used for every call.
+//
+// Host functions imported from module "sjmb", pointers and lengths are i32:
+//   reply(ptr, len)                              reply to the channel or nick the message came from
+//   send_op(ptr, len) -> i32                     queue an IRC op, e.g. {"op":"mode_v","channel":"#c","nick":"n"}
+//   config_get(key, key_len, out, out_cap) -> i32   value from the module's own config section as JSON
+//   http_get(url, url_len, out, out_cap) -> i32     response body, truncated to out_cap
+// Functions returning i32 return the number of bytes (that would be) written, or -1 on error.
+//
+// The only ops are mode_o and mode_v for the sender on the channel the message came from,
+// mode_o only if the sender passes mode_o_acl like with the open commands. Anything else is
+// refused, unknown ops already by send_op.
+//
+// A module gets at most WASM_OUTPUT_MAX replies and ops and WASM_HOST_CALLS_MAX host calls
+// per invocation, and strings it passes in are at most WASM_STRING_MAX bytes.
+//
+// Fuel is what really stops a module: a pure compute loop never returns to the host, so the
+// time limit is turned into fuel (WASM_FUEL_PER_MS) and only checked as such in host calls.
+
+use std::{fs, path::Path, time::Instant};
+
+use futures::future::BoxFuture;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::*;
+
+const WASM_MODULE: &str = "sjmb";
+// a conservative guess of what the interpreter gets through in a millisecond
+const WASM_FUEL_PER_MS: u64 = 100_000;
+// in bytes, the longest string a host function reads from guest memory
+const WASM_STRING_MAX: usize = 64 * 1024;
+// per invocation
+const WASM_HOST_CALLS_MAX: usize = 64;
+const WASM_OUTPUT_MAX: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WasmPluginConfig {
+    // empty means no WASM plugins are loaded
+    pub plugin_dir: String,
+    // fuel is roughly the number of executed instructions
+    pub fuel: u64,
+    // max linear memory per instance, in bytes
+    pub max_memory: usize,
+    // time limit per invocation in milliseconds, also caps the fuel
+    pub timeout: u64,
+    // what config_get sees, by module name (the file name without .wasm)
+    pub config: HashMap<String, serde_json::Value>,
+}
+
+impl Default for WasmPluginConfig {
+    fn default() -> Self {
+        Self {
+            plugin_dir: String::new(),
+            fuel: 10_000_000,
+            max_memory: 4 * 1024 * 1024,
+            timeout: 5000,
+            config: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WasmOp {
+    ModeO { channel: String, nick: String },
+    ModeV { channel: String, nick: String },
+}
+
+#[derive(Debug, Default)]
+struct WasmOutput {
+    handled: bool,
+    replies: Vec<String>,
+    ops: Vec<WasmOp>,
+}
+
+struct HostState {
+    limits: StoreLimits,
+    deadline: Instant,
+    calls: usize,
+    config: Arc<serde_json::Value>,
+    output: WasmOutput,
+}
+
+struct WasmModule {
+    name: String,
+    module: Arc<Module>,
+    config: Arc<serde_json::Value>,
+}
+
+pub struct WasmPlugins {
+    engine: Engine,
+    modules: RwLock<Arc<Vec<WasmModule>>>,
+}
+
+impl Default for WasmPlugins {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WasmPlugins {
+    pub fn new() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Self {
+            engine: Engine::new(&config),
+            modules: RwLock::new(Arc::new(Vec::new())),
+        }
+    }
+
+    async fn load(&self, bot: &Arc<IrcBot>) -> anyhow::Result<()> {
+        let (plugin_dir, mut configs) = {
+            let cfg = &bot.config.read().await.wasm_plugins;
+            (cfg.plugin_dir.clone(), cfg.config.clone())
+        };
+        let mut modules = Vec::new();
+        if !plugin_dir.is_empty() {
+            let mut paths = fs::read_dir(&plugin_dir)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "wasm"))
+                .collect::<Vec<_>>();
+            // load in a predictable order
+            paths.sort();
+            for path in paths {
+                match self.load_module(&path, &mut configs) {
+                    Ok(m) => {
+                        info!("Loaded WASM plugin {}", m.name);
+                        modules.push(m);
+                    }
+                    Err(e) => error!("Could not load WASM plugin {}: {e}", path.display()),
+                }
+            }
+        }
+        *self.modules.write().await = Arc::new(modules);
+        Ok(())
+    }
+
+    fn load_module(&self, path: &Path, configs: &mut HashMap<String, serde_json::Value>) -> anyhow::Result<WasmModule> {
+        let wasm = fs::read(path)?;
+        let module = Module::new(&self.engine, &wasm).map_err(|e| anyhow!("{e}"))?;
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let config = configs.remove(&name).unwrap_or_default();
+        Ok(WasmModule {
+            name,
+            module: Arc::new(module),
+            config: Arc::new(config),
+        })
+    }
+
+    async fn handle_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> anyhow::Result<bool> {
+        let modules = self.modules.read().await.clone();
+        if modules.is_empty() {
+            return Ok(false);
+        }
+
+        let limits = bot.config.read().await.wasm_plugins.clone();
+        let input = serde_json::to_vec(&ctx)?;
+
+        for m in modules.iter() {
+            let (engine, module, limits, config, input) = (
+                self.engine.clone(),
+                m.module.clone(),
+                limits.clone(),
+                m.config.clone(),
+                input.clone(),
+            );
+            // the fuel stops the guest, this only stops waiting for a host call that hangs
+            let timeout = Duration::from_millis(limits.timeout);
+            let job = tokio::task::spawn_blocking(move || run_wasm(&engine, &module, &limits, config, &input));
+
+            let output = match tokio::time::timeout(timeout, job).await {
+                Ok(Ok(Ok(output))) => output,
+                Ok(Ok(Err(e))) => {
+                    error!("WASM plugin {} failed: {e}", m.name);
+                    continue;
+                }
+                Ok(Err(e)) => {
+                    error!("WASM plugin {} panicked: {e}", m.name);
+                    continue;
+                }
+                Err(_) => {
+                    error!("WASM plugin {} timed out", m.name);
+                    continue;
+                }
+            };
+
+            debug!("WASM plugin {} output: {output:?}", m.name);
+            for reply in &output.replies {
+                ctx.reply(&bot, reply).await?;
+            }
+            for op in output.ops {
+                let op = {
+                    let cfg = bot.config.read().await;
+                    let mode_o_acl = cfg.mode_o_acl_rt.as_ref().ok_or_else(|| anyhow!("no mode_o_acl_rt"))?;
+                    wasm_irc_op(op, &ctx, mode_o_acl)
+                };
+                match op {
+                    Ok(op) => {
+                        bot.clone().new_op(op).await?;
+                    }
+                    Err(e) => info!("WASM plugin {} op refused: {e}", m.name),
+                }
+            }
+            if output.handled {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl BotPlugin for WasmPlugins {
+    fn name(&self) -> &str {
+        "wasm"
+    }
+
+    fn register<'a>(&'a self, bot: &'a Arc<IrcBot>) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(self.load(bot))
+    }
+
+    fn on_message(&self, bot: Arc<IrcBot>, ctx: MsgContext) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(self.handle_message(bot, ctx))
+    }
+
+    fn on_reload(&self, bot: Arc<IrcBot>) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move { self.load(&bot).await })
+    }
+}
+
+// The same rules as for the open +o and +v commands, for the sender and the channel only
+fn wasm_irc_op(op: WasmOp, ctx: &MsgContext, mode_o_acl: &ReAcl) -> anyhow::Result<IrcOp> {
+    let (WasmOp::ModeO { channel, nick } | WasmOp::ModeV { channel, nick }) = &op;
+    if !ctx.channel.as_ref().is_some_and(|c| c.eq_ignore_ascii_case(channel)) {
+        bail!("{channel} is not the channel of the message");
+    }
+    if !nick.eq_ignore_ascii_case(&ctx.nick) {
+        bail!("{nick} is not the sender of the message");
+    }
+    match op {
+        WasmOp::ModeO { channel, nick } => match mode_o_acl.re_match(&ctx.userhost) {
+            Some((i, s)) => {
+                info!("ACL match {} at index {i}: {s}", ctx.userhost);
+                Ok(IrcOp::ModeOper(channel, nick))
+            }
+            None => bail!("ACL check failed for {}", ctx.userhost),
+        },
+        WasmOp::ModeV { channel, nick } => Ok(IrcOp::ModeVoice(channel, nick)),
+    }
+}
+
+fn run_wasm(
+    engine: &Engine,
+    module: &Module,
+    limits: &WasmPluginConfig,
+    config: Arc<serde_json::Value>,
+    input: &[u8],
+) -> anyhow::Result<WasmOutput> {
+    let state = HostState {
+        limits: StoreLimitsBuilder::new()
+            .memory_size(limits.max_memory)
+            .instances(1)
+            .build(),
+        deadline: Instant::now() + Duration::from_millis(limits.timeout),
+        calls: 0,
+        config,
+        output: WasmOutput::default(),
+    };
+    let mut store = Store::new(engine, state);
+    store.limiter(|s| &mut s.limits);
+    let fuel = limits.fuel.min(limits.timeout.saturating_mul(WASM_FUEL_PER_MS));
+    store.set_fuel(fuel).map_err(|e| anyhow!("{e}"))?;
+
+    let linker = wasm_linker(engine)?;
+    let instance = linker
+        .instantiate(&mut store, module)
+        .and_then(|pre| pre.start(&mut store))
+        .map_err(|e| anyhow!("{e}"))?;
+
+    let memory = instance
+        .get_memory(&store, "memory")
+        .ok_or_else(|| anyhow!("no exported memory"))?;
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&store, "alloc")
+        .map_err(|e| anyhow!("alloc: {e}"))?;
+    let on_message = instance
+        .get_typed_func::<(i32, i32), i32>(&store, "on_message")
+        .map_err(|e| anyhow!("on_message: {e}"))?;
+
+    let len = i32::try_from(input.len())?;
+    let ptr = alloc.call(&mut store, len).map_err(|e| anyhow!("{e}"))?;
+    memory
+        .write(&mut store, usize::try_from(ptr)?, input)
+        .map_err(|e| anyhow!("{e}"))?;
+    let handled = on_message.call(&mut store, (ptr, len)).map_err(|e| anyhow!("{e}"))?;
+
+    let mut output = std::mem::take(&mut store.data_mut().output);
+    output.handled = handled != 0;
+    Ok(output)
+}
+
+fn wasm_linker(engine: &Engine) -> anyhow::Result<Linker<HostState>> {
+    let mut linker = Linker::<HostState>::new(engine);
+
+    linker
+        .func_wrap(
+            WASM_MODULE,
+            "reply",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+                host_call(&mut caller)?;
+                check_output(&caller)?;
+                let reply = read_string(&caller, ptr, len)?;
+                caller.data_mut().output.replies.push(reply);
+                Ok(())
+            },
+        )
+        .map_err(|e| anyhow!("{e}"))?;
+
+    linker
+        .func_wrap(
+            WASM_MODULE,
+            "send_op",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> Result<i32, wasmi::Error> {
+                host_call(&mut caller)?;
+                check_output(&caller)?;
+                let op = read_string(&caller, ptr, len)?;
+                match serde_json::from_str::<WasmOp>(&op) {
+                    Ok(op) => {
+                        caller.data_mut().output.ops.push(op);
+                        Ok(0)
+                    }
+                    Err(e) => {
+                        error!("WASM plugin sent a bad op {op:?}: {e}");
+                        Ok(-1)
+                    }
+                }
+            },
+        )
+        .map_err(|e| anyhow!("{e}"))?;
+
+    linker
+        .func_wrap(
+            WASM_MODULE,
+            "config_get",
+            |mut caller: Caller<'_, HostState>,
+             key: i32,
+             key_len: i32,
+             out: i32,
+             out_cap: i32|
+             -> Result<i32, wasmi::Error> {
+                host_call(&mut caller)?;
+                let key = read_string(&caller, key, key_len)?;
+                let value = match caller.data().config.get(&key) {
+                    Some(v) => v.to_string(),
+                    None => return Ok(-1),
+                };
+                write_bytes(&mut caller, out, out_cap, value.as_bytes())
+            },
+        )
+        .map_err(|e| anyhow!("{e}"))?;
+
+    linker
+        .func_wrap(
+            WASM_MODULE,
+            "http_get",
+            |mut caller: Caller<'_, HostState>,
+             url: i32,
+             url_len: i32,
+             out: i32,
+             out_cap: i32|
+             -> Result<i32, wasmi::Error> {
+                host_call(&mut caller)?;
+                let url = read_string(&caller, url, url_len)?;
+                let remaining = caller.data().deadline.saturating_duration_since(Instant::now());
+                // we are on a blocking thread of the runtime, see handle_message()
+                let body = tokio::runtime::Handle::current()
+                    .block_on(async { tokio::time::timeout(remaining, get_body(&url)).await });
+                match body {
+                    Ok(Ok((body, _ct))) => {
+                        let body = body.as_bytes();
+                        let n = body.len().min(usize::try_from(out_cap).unwrap_or(0));
+                        write_bytes(&mut caller, out, out_cap, &body[..n])
+                    }
+                    Ok(Err(e)) => {
+                        info!("WASM plugin http_get {url} failed: {e}");
+                        Ok(-1)
+                    }
+                    Err(_) => Err(wasmi::Error::new("time limit exceeded")),
+                }
+            },
+        )
+        .map_err(|e| anyhow!("{e}"))?;
+
+    Ok(linker)
+}
+
+fn host_call(caller: &mut Caller<'_, HostState>) -> Result<(), wasmi::Error> {
+    let state = caller.data_mut();
+    state.calls += 1;
+    if state.calls > WASM_HOST_CALLS_MAX {
+        return Err(wasmi::Error::new("too many host calls"));
+    }
+    if Instant::now() > state.deadline {
+        return Err(wasmi::Error::new("time limit exceeded"));
+    }
+    Ok(())
+}
+
+fn check_output(caller: &Caller<'_, HostState>) -> Result<(), wasmi::Error> {
+    let output = &caller.data().output;
+    if output.replies.len() + output.ops.len() >= WASM_OUTPUT_MAX {
+        return Err(wasmi::Error::new("too many replies and ops"));
+    }
+    Ok(())
+}
+
+fn guest_memory(caller: &Caller<'_, HostState>) -> Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("no exported memory"))
+}
+
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    let (ptr, len) = match (usize::try_from(ptr), usize::try_from(len)) {
+        (Ok(p), Ok(l)) => (p, l),
+        _ => return Err(wasmi::Error::new("bad pointer")),
+    };
+    if len > WASM_STRING_MAX {
+        return Err(wasmi::Error::new("string too long"));
+    }
+    // bounds checked before anything is copied out of it
+    let data = guest_memory(caller)?.data(caller);
+    let bytes = ptr
+        .checked_add(len)
+        .and_then(|end| data.get(ptr..end))
+        .ok_or_else(|| wasmi::Error::new("bad pointer"))?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// Returns the full length of `data`, only writing it if it fits in `out_cap` bytes
+fn write_bytes(caller: &mut Caller<'_, HostState>, out: i32, out_cap: i32, data: &[u8]) -> Result<i32, wasmi::Error> {
+    let len = i32::try_from(data.len()).map_err(|_| wasmi::Error::new("value too large"))?;
+    if len > out_cap {
+        return Ok(len);
+    }
+    let out = usize::try_from(out).map_err(|_| wasmi::Error::new("bad pointer"))?;
+    guest_memory(caller)?
+        .write(caller, out, data)
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Echoes its input back as a reply and voices alice
+    const TEST_PLUGIN: &str = r##"
+        (module
+          (import "sjmb" "reply" (func $reply (param i32 i32)))
+          (import "sjmb" "send_op" (func $send_op (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"op\":\"mode_v\",\"channel\":\"#test\",\"nick\":\"alice\"}")
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "on_message") (param $ptr i32) (param $len i32) (result i32)
+            (call $reply (local.get $ptr) (local.get $len))
+            (drop (call $send_op (i32.const 0) (i32.const 48)))
+            (i32.const 1)))
+    "##;
+
+    const LOOP_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "on_message") (param i32 i32) (result i32)
+            (loop $forever (br $forever))
+            (i32.const 0)))
+    "#;
+
+    fn load(engine: &Engine, wat: &str) -> Module {
+        let wasm = wat::parse_str(wat).expect("test module should assemble");
+        Module::new(engine, &wasm).expect("test module should load")
+    }
+
+    #[test]
+    fn wasm_plugin_can_reply_and_queue_ops() {
+        let plugins = WasmPlugins::new();
+        let module = load(&plugins.engine, TEST_PLUGIN);
+        let output = run_wasm(
+            &plugins.engine,
+            &module,
+            &WasmPluginConfig::default(),
+            Arc::new(serde_json::Value::Null),
+            b"hello",
+        )
+        .expect("test plugin should run");
+
+        assert!(output.handled);
+        assert_eq!(output.replies, vec!["hello".to_string()]);
+        assert!(matches!(
+            output.ops.as_slice(),
+            [WasmOp::ModeV { channel, nick }] if channel == "#test" && nick == "alice"
+        ));
+    }
+
+    #[test]
+    fn wasm_ops_only_for_the_sender_on_the_channel() {
+        let acl = ReAcl::new(&vec![r"^alice@trusted\.example$".to_string()]).unwrap();
+        let ctx = MsgContext {
+            nick: "alice".to_string(),
+            userhost: "alice@trusted.example".to_string(),
+            channel: Some("#test".to_string()),
+            ..Default::default()
+        };
+        let op = |op: &str, channel: &str, nick: &str| {
+            WasmOp::deserialize(serde_json::json!({"op": op, "channel": channel, "nick": nick})).unwrap()
+        };
+
+        assert!(matches!(
+            wasm_irc_op(op("mode_o", "#Test", "Alice"), &ctx, &acl),
+            Ok(IrcOp::ModeOper(..))
+        ));
+        assert!(matches!(
+            wasm_irc_op(op("mode_v", "#test", "alice"), &ctx, &acl),
+            Ok(IrcOp::ModeVoice(..))
+        ));
+        assert!(wasm_irc_op(op("mode_o", "#other", "alice"), &ctx, &acl).is_err());
+        assert!(wasm_irc_op(op("mode_v", "#test", "mallory"), &ctx, &acl).is_err());
+
+        let untrusted = MsgContext {
+            userhost: "alice@elsewhere.example".to_string(),
+            ..ctx.clone()
+        };
+        assert!(wasm_irc_op(op("mode_o", "#test", "alice"), &untrusted, &acl).is_err());
+        assert!(wasm_irc_op(op("mode_v", "#test", "alice"), &untrusted, &acl).is_ok());
+        let private = MsgContext { channel: None, ..ctx };
+        assert!(wasm_irc_op(op("mode_v", "#test", "alice"), &private, &acl).is_err());
+        assert!(serde_json::from_str::<WasmOp>(r#"{"op":"nick","nick":"root"}"#).is_err());
+    }
+
+    #[test]
+    fn wasm_host_calls_are_bounded() {
+        let plugins = WasmPlugins::new();
+        let limits = WasmPluginConfig::default();
+        let run = |wat: &str| {
+            run_wasm(
+                &plugins.engine,
+                &load(&plugins.engine, wat),
+                &limits,
+                Arc::default(),
+                b"",
+            )
+        };
+
+        let huge = r#"
+            (module
+              (import "sjmb" "reply" (func $reply (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "on_message") (param i32 i32) (result i32)
+                (call $reply (i32.const 0) (i32.const 0x7fffffff))
+                (i32.const 1)))
+        "#;
+        let err = run(huge).unwrap_err();
+        assert!(err.to_string().contains("string too long"), "{err}");
+
+        let chatty = r#"
+            (module
+              (import "sjmb" "reply" (func $reply (param i32 i32)))
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "on_message") (param i32 i32) (result i32)
+                (loop $again
+                  (call $reply (i32.const 0) (i32.const 1))
+                  (br $again))
+                (i32.const 1)))
+        "#;
+        let err = run(chatty).unwrap_err();
+        assert!(err.to_string().contains("too many replies"), "{err}");
+    }
+
+    #[test]
+    fn wasm_plugin_runs_out_of_fuel() {
+        let plugins = WasmPlugins::new();
+        let module = load(&plugins.engine, LOOP_PLUGIN);
+        let limits = WasmPluginConfig {
+            fuel: 10_000,
+            ..Default::default()
+        };
+        assert!(run_wasm(&plugins.engine, &module, &limits, Arc::default(), b"").is_err());
+    }
+
+    #[test]
+    fn wasm_timeout_caps_the_fuel() {
+        let plugins = WasmPlugins::new();
+        let module = load(&plugins.engine, LOOP_PLUGIN);
+        let limits = WasmPluginConfig {
+            fuel: u64::MAX,
+            timeout: 1,
+            ..Default::default()
+        };
+        let err = run_wasm(&plugins.engine, &module, &limits, Arc::default(), b"").unwrap_err();
+        assert!(err.to_string().contains("fuel"), "{err}");
+    }
+}
+
+// EOF
@@ -0,0 +1,220 @@
+// reconnect.rs
+
+// Picks the server and the delay for each connection attempt. Failed and short-lived
+// connections back off exponentially with jitter and move on to the next server. K-lines
+// and throttling wait at least their own configured delays, retrying sooner only makes
+// them worse.
+
+use crate::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    // "host" or "host:port", tried in order after the server in the irc config
+    pub servers: Vec<String>,
+    // in milliseconds
+    pub backoff_initial: u64,
+    pub backoff_max: u64,
+    // percentage of the delay added or removed at random
+    pub jitter: u64,
+    // in milliseconds, the minimum delay after a K-line/ban or a throttle
+    pub banned_delay: u64,
+    pub throttled_delay: u64,
+    // in milliseconds, a connection that lasted this long resets the backoff
+    pub stable_after: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            backoff_initial: 10_000,
+            backoff_max: 600_000,
+            jitter: 20,
+            banned_delay: 3_600_000,
+            throttled_delay: 60_000,
+            stable_after: 300_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disconnect {
+    Transient,
+    Throttled,
+    Banned,
+}
+
+impl Disconnect {
+    // Classifies the last ERROR (or ban numeric) the server sent before closing the link
+    pub fn classify(reason: Option<&str>) -> Self {
+        let Some(reason) = reason.map(str::to_lowercase) else {
+            return Disconnect::Transient;
+        };
+        if ["k-line", "g-line", "z-line", "d-line", "banned"]
+            .iter()
+            .any(|s| reason.contains(s))
+        {
+            Disconnect::Banned
+        } else if reason.contains("throttl") || reason.contains("too fast") {
+            Disconnect::Throttled
+        } else {
+            Disconnect::Transient
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddr {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl ServerAddr {
+    pub fn parse(s: &str) -> Self {
+        match s.rsplit_once(':').map(|(host, port)| (host, port.parse::<u16>())) {
+            Some((host, Ok(port))) => ServerAddr {
+                host: host.to_string(),
+                port: Some(port),
+            },
+            _ => ServerAddr {
+                host: s.to_string(),
+                port: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Reconnect {
+    attempt: u32,
+    // 0 is the server in the irc config, then the fallbacks
+    server_idx: usize,
+}
+
+impl Reconnect {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // None means the server in the irc config
+    pub fn server(&self, cfg: &ReconnectConfig) -> Option<ServerAddr> {
+        match self.server_idx {
+            0 => None,
+            i => cfg.servers.get(i - 1).map(|s| ServerAddr::parse(s)),
+        }
+    }
+
+    // Call when a connection has ended or failed, returns how long to wait before the next attempt
+    pub fn next(&mut self, cfg: &ReconnectConfig, reason: Disconnect, uptime: Duration) -> Duration {
+        let stable = uptime >= Duration::from_millis(cfg.stable_after);
+        if stable {
+            self.attempt = 0;
+        }
+        // a server that worked for a while is worth retrying after a plain disconnect
+        if !stable || reason != Disconnect::Transient {
+            self.server_idx = (self.server_idx + 1) % (cfg.servers.len() + 1);
+        }
+
+        let backoff = cfg
+            .backoff_initial
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(cfg.backoff_max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let delay = match reason {
+            Disconnect::Transient => backoff,
+            Disconnect::Throttled => backoff.max(cfg.throttled_delay),
+            Disconnect::Banned => backoff.max(cfg.banned_delay),
+        };
+        Duration::from_millis(with_jitter(delay, cfg.jitter))
+    }
+}
+
+fn with_jitter(delay: u64, percent: u64) -> u64 {
+    let spread = delay.saturating_mul(percent.min(100)) / 100;
+    match spread {
+        0 => delay,
+        s => delay - s + rand::random_range(0..=2 * s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> ReconnectConfig {
+        ReconnectConfig {
+            servers: vec!["irc.example.net:6697".to_string(), "irc.example.org".to_string()],
+            jitter: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn disconnects_are_classified() {
+        assert_eq!(Disconnect::classify(None), Disconnect::Transient);
+        assert_eq!(
+            Disconnect::classify(Some("Closing Link: sjmb[1.2.3.4] (Ping timeout: 240 seconds)")),
+            Disconnect::Transient
+        );
+        assert_eq!(
+            Disconnect::classify(Some("Closing Link: 1.2.3.4 (K-Lined: spam)")),
+            Disconnect::Banned
+        );
+        assert_eq!(
+            Disconnect::classify(Some("Closing Link: 1.2.3.4 (Throttled: Reconnecting too fast)")),
+            Disconnect::Throttled
+        );
+    }
+
+    #[test]
+    fn failures_back_off_and_rotate_servers() {
+        let cfg = cfg();
+        let mut rc = Reconnect::new();
+        assert_eq!(rc.server(&cfg), None);
+
+        let delays = (0..3)
+            .map(|_| rc.next(&cfg, Disconnect::Transient, Duration::ZERO).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [10_000, 20_000, 40_000]);
+        // three failures went through all three servers and back to the first
+        assert_eq!(rc.server(&cfg), None);
+        rc.next(&cfg, Disconnect::Transient, Duration::ZERO);
+        assert_eq!(
+            rc.server(&cfg),
+            Some(ServerAddr {
+                host: "irc.example.net".to_string(),
+                port: Some(6697)
+            })
+        );
+
+        // a stable connection resets the backoff and keeps its server
+        let uptime = Duration::from_millis(cfg.stable_after);
+        assert_eq!(rc.next(&cfg, Disconnect::Transient, uptime).as_millis(), 10_000);
+        assert_eq!(rc.server(&cfg).map(|s| s.port), Some(Some(6697)));
+    }
+
+    #[test]
+    fn bans_and_throttles_wait_longer() {
+        let cfg = cfg();
+        let mut rc = Reconnect::new();
+        assert_eq!(
+            rc.next(&cfg, Disconnect::Throttled, Duration::ZERO).as_millis(),
+            u128::from(cfg.throttled_delay)
+        );
+        assert_eq!(
+            rc.next(&cfg, Disconnect::Banned, Duration::ZERO).as_millis(),
+            u128::from(cfg.banned_delay)
+        );
+        assert_eq!(
+            rc.server(&cfg),
+            Some(ServerAddr {
+                host: "irc.example.org".to_string(),
+                port: None
+            })
+        );
+    }
+}
+
+// EOF
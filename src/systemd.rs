@@ -0,0 +1,180 @@
+// systemd.rs
+
+// sd_notify(3) for running as a `Type=notify` service, without linking libsystemd: the
+// messages are plain datagrams to $NOTIFY_SOCKET. Outside systemd all of this is a no-op.
+//
+// READY is sent on RPL_WELCOME. The watchdog is only pinged from the run loop while we are
+// registered and the lag monitor is happy, so a wedged connection gets the bot restarted.
+
+use std::{
+    os::unix::net::UnixDatagram,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use crate::*;
+
+#[derive(Debug, Default)]
+struct NotifyState {
+    last_watchdog: Option<Instant>,
+    last_status: String,
+}
+
+#[derive(Debug, Default)]
+pub struct SystemdNotify {
+    socket: Option<String>,
+    watchdog: Option<Duration>,
+    state: Mutex<NotifyState>,
+}
+
+impl SystemdNotify {
+    pub fn from_env() -> Self {
+        let socket = env::var("NOTIFY_SOCKET").ok().filter(|s| !s.is_empty());
+        let watchdog = watchdog_interval(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+        if let Some(socket) = &socket {
+            info!("systemd notify socket {socket}, watchdog {watchdog:?}");
+        }
+        Self {
+            socket,
+            watchdog,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.lock().last_status = status.to_string();
+        self.send(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    // Sent only when changed, this is called on every tick
+    pub fn status(&self, status: &str) {
+        {
+            let mut state = self.lock();
+            if state.last_status == status {
+                return;
+            }
+            state.last_status = status.to_string();
+        }
+        self.send(&format!("STATUS={status}"));
+    }
+
+    // Pings at twice the rate systemd expects, as recommended
+    pub fn watchdog(&self) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        let now = Instant::now();
+        {
+            let mut state = self.lock();
+            if state
+                .last_watchdog
+                .is_some_and(|last| now.duration_since(last) < interval / 2)
+            {
+                return;
+            }
+            state.last_watchdog = Some(now);
+        }
+        self.send("WATCHDOG=1");
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1\nSTATUS=Shutting down");
+    }
+
+    fn send(&self, msg: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        trace!("sd_notify: {msg:?}");
+        if let Err(e) = notify_send(socket, msg) {
+            error!("sd_notify to {socket} failed: {e}");
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, NotifyState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn notify_send(socket: &str, msg: &str) -> anyhow::Result<()> {
+    let sock = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        // abstract namespace
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(msg.as_bytes(), &addr)?;
+        }
+        None => {
+            sock.send_to(msg.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+// $WATCHDOG_USEC applies to us unless $WATCHDOG_PID names some other process
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, my_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(my_pid)
+    {
+        return None;
+    }
+    match usec?.parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_from_env() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("43"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn notify_sends_datagrams() {
+        let path = env::temp_dir().join(format!("sjmb-notify-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let rx = UnixDatagram::bind(&path).unwrap();
+        let notify = SystemdNotify {
+            socket: Some(path.to_string_lossy().into_owned()),
+            watchdog: Some(Duration::from_secs(30)),
+            state: Mutex::default(),
+        };
+
+        let mut buf = [0u8; 256];
+        notify.ready("sjmb on irc.example.net:6667, 1 channels");
+        let n = rx.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=sjmb on irc.example.net:6667, 1 channels");
+
+        // unchanged status and a too early watchdog ping are not sent
+        notify.status("sjmb on irc.example.net:6667, 1 channels");
+        notify.watchdog();
+        notify.watchdog();
+        notify.status("sjmb on irc.example.net:6667, 2 channels");
+        let n = rx.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        let n = rx.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=sjmb on irc.example.net:6667, 2 channels");
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+// EOF
@@ -0,0 +1,201 @@
+// urljob.rs
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+    time::Instant,
+};
+
+use chrono_tz::Tz;
+use tokio::sync::Mutex;
+
+use crate::*;
+
+// Network and database bound work is done by this many workers, outside of the throttled op queue
+const URL_WORKERS: usize = 4;
+const URL_QUEUE_CAPACITY: usize = 42;
+
+#[derive(Debug, Clone)]
+pub enum UrlJob {
+    // say the title of the url to the channel
+    Title(String, String),
+    // fetch the url and say what the output filter matches
+    Fetch(String, String, Regex),
+    // insert the url into the url log, first complaining if it was seen before
+    Log(UrlLogJob),
+}
+
+#[derive(Debug, Clone)]
+pub struct UrlLogJob {
+    pub db: DbCtx,
+    pub url: String,
+    pub channel: String,
+    pub nick: String,
+    pub ts: i64,
+    // timezone and expiry in days for the duplicate check, if it is enabled
+    pub dup_check: Option<(Tz, i64)>,
+}
+
+#[derive(Clone)]
+pub struct UrlWorkers {
+    sender: mpsc::Sender<UrlJob>,
+    // queued or running jobs
+    pending: Arc<AtomicUsize>,
+}
+
+impl UrlWorkers {
+    pub(crate) fn start(out: Outbound) -> Self {
+        let (sender, rx) = mpsc::channel::<UrlJob>(URL_QUEUE_CAPACITY);
+        let rx = Arc::new(Mutex::new(rx));
+        let pending = Arc::new(AtomicUsize::new(0));
+        for i in 0..URL_WORKERS {
+            let (out, rx, pending) = (out.clone(), rx.clone(), pending.clone());
+            tokio::spawn(async move {
+                debug!("Starting url worker #{i}");
+                read_url_queue(out, rx, pending).await;
+            });
+        }
+        Self { sender, pending }
+    }
+
+    pub async fn submit(&self, job: UrlJob) -> anyhow::Result<()> {
+        self.pending.fetch_add(1, AtomicOrdering::SeqCst);
+        let res = queue_send(&self.sender, job, "url").await;
+        if res.is_err() {
+            self.pending.fetch_sub(1, AtomicOrdering::SeqCst);
+        }
+        res
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(AtomicOrdering::SeqCst)
+    }
+
+    // Returns false if jobs were still pending at the deadline
+    pub async fn wait_idle(&self, deadline: Instant) -> bool {
+        wait_until(deadline, || self.pending.load(AtomicOrdering::SeqCst) == 0).await
+    }
+}
+
+async fn read_url_queue(out: Outbound, rx: Arc<Mutex<mpsc::Receiver<UrlJob>>>, pending: Arc<AtomicUsize>) {
+    loop {
+        // only hold the lock while waiting, so the other workers can pick up jobs meanwhile
+        let Some(job) = rx.lock().await.recv().await else {
+            break;
+        };
+        debug!("read_url_queue: new job: {job:?}");
+        if let Err(e) = url_job_dispatch(out.clone(), job).await {
+            error!("{e}");
+        }
+        pending.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+}
+
+async fn url_job_dispatch(out: Outbound, job: UrlJob) -> anyhow::Result<()> {
+    match job {
+        UrlJob::Title(url, channel) => job_handle_urltitle(out, url, channel).await,
+        UrlJob::Fetch(url, channel, output_filter) => job_handle_urlfetch(out, url, channel, output_filter).await,
+        UrlJob::Log(log) => {
+            // the check has to see the database before this url is inserted
+            if let Some((tz, exp_days)) = log.dup_check
+                && let Err(e) = job_handle_urlcheck(out, &log.db, &log.url, &log.channel, tz, exp_days).await
+            {
+                error!("{e}");
+            }
+            job_handle_urllog(log).await
+        }
+    }
+}
+
+async fn job_handle_urlcheck(
+    out: Outbound,
+    db: &DbCtx,
+    url: &str,
+    channel: &str,
+    tz: Tz,
+    exp_days: i64,
+) -> anyhow::Result<()> {
+    debug!("job_handle_urlcheck(): url {url}");
+    if let Some(old) = db_check_url(db, url, channel, exp_days * 86400).await?
+        && let (Some(first), Some(last)) = (old.first, old.last)
+    {
+        let ts_first = DateTime::from_timestamp(first, 0)
+            .unwrap_or_default()
+            .with_timezone(&tz);
+
+        match old.cnt.cmp(&1) {
+            Ordering::Equal => {
+                say_low(&out, channel, &format!("Wanha URL, nähty {ts_first}")).await?;
+            }
+            Ordering::Greater => {
+                let ts_last = DateTime::from_timestamp(last, 0).unwrap_or_default().with_timezone(&tz);
+                say_low(
+                    &out,
+                    channel,
+                    &format!(
+                        "Wanha URL, nähty {} kertaa, ensin {ts_first} ja viimeksi {ts_last}",
+                        old.cnt
+                    ),
+                )
+                .await?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn job_handle_urlfetch(out: Outbound, url: String, channel: String, output_filter: Regex) -> anyhow::Result<()> {
+    debug!("job_handle_urlfetch()");
+    if let Some((body, _ct)) = get_text_body(&url).await? {
+        for res_cap in output_filter.captures_iter(&body) {
+            let res_str = &res_cap[1];
+            let say = format!("--> {res_str}");
+            out.privmsg(&channel, &say).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn job_handle_urllog(log: UrlLogJob) -> anyhow::Result<()> {
+    let UrlLogJob {
+        db,
+        url,
+        channel: chan,
+        nick,
+        ts,
+        ..
+    } = log;
+    debug!("job_handle_urllog(): insert url {url}");
+    info!(
+        "Urllog: inserted {} row(s)",
+        db_add_url(&db, &UrlCtx { ts, chan, nick, url },).await?
+    );
+    Ok(())
+}
+
+async fn job_handle_urltitle(out: Outbound, url: String, channel: String) -> anyhow::Result<()> {
+    debug!("job_handle_urltitle(): fetching url {url}");
+    if let Some((body, _ct)) = get_text_body(&url).await? {
+        debug!("Parsing title from body: url {url}");
+        let html = webpage::HTML::from_string(body, None)?;
+
+        if let Some(title) = html.title
+            // ignore titles that are just the url repeated
+            && title != url
+        {
+            // Replace all consecutive whitespace, including newlines etc with a single space,
+            // long titles are split by the outbound scheduler
+            let say = format!("\"{}\"", title.ws_collapse());
+            say_low(&out, &channel, &say).await?;
+        }
+    }
+    Ok(())
+}
+
+// Automatic output, it can wait behind replies and ops or be dropped when stale
+async fn say_low(out: &Outbound, channel: &str, msg: &str) -> anyhow::Result<()> {
+    out.send(OutPriority::Low, OutKind::Privmsg, channel, msg).await
+}
+
+// EOF
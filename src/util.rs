@@ -9,6 +9,9 @@ const TS_FMT_SHORT: &str = "%b %d %H:%M";
 const TS_FMT_SHORT_YEAR: &str = "%Y %b %d %H:%M";
 const TS_NONE: &str = "(none)";
 
+// in milliseconds
+const WAIT_POLL_INTERVAL: u64 = 50;
+
 pub fn ts_fmt(fmt: &str, ts: i64) -> String {
     if ts == 0 {
         TS_NONE.to_string()
@@ -37,6 +40,29 @@ impl TimeStampFormats for i64 {
     }
 }
 
+pub trait HumanDuration {
+    fn human_duration(self) -> String;
+}
+
+impl HumanDuration for i64 {
+    // seconds as e.g. "2d 3h 4m 5s", leaving out the leading zero units
+    fn human_duration(self) -> String {
+        let secs = self.max(0);
+        let parts = [
+            (secs / 86400, "d"),
+            (secs / 3600 % 24, "h"),
+            (secs / 60 % 60, "m"),
+            (secs % 60, "s"),
+        ];
+        let parts = parts
+            .iter()
+            .skip_while(|(n, unit)| *n == 0 && *unit != "s")
+            .map(|(n, unit)| format!("{n}{unit}"))
+            .collect::<Vec<_>>();
+        parts.join(" ")
+    }
+}
+
 pub trait CollapseWhiteSpace {
     fn ws_collapse(self) -> String;
 }
@@ -47,6 +73,24 @@ impl CollapseWhiteSpace for &str {
     }
 }
 
+// Polls `done` until it returns true or the deadline passes, returns the last result
+pub async fn wait_until(deadline: std::time::Instant, mut done: impl FnMut() -> bool) -> bool {
+    loop {
+        if done() {
+            return true;
+        }
+        if std::time::Instant::now() >= deadline {
+            return false;
+        }
+        sleep(Duration::from_millis(WAIT_POLL_INTERVAL)).await;
+    }
+}
+
+// By the prefix, the usual channel types
+pub fn is_channel_name(target: &str) -> bool {
+    target.starts_with(['#', '&', '!', '+'])
+}
+
 pub fn get_wild<'a, T>(map: &'a HashMap<String, T>, key: &str) -> Option<&'a T> {
     map.get(key).or_else(|| map.get("*"))
 }
@@ -66,6 +110,16 @@ pub async fn get_text_body(url_s: &str) -> anyhow::Result<Option<(String, String
 }
 
 pub async fn get_body(url_s: &str) -> anyhow::Result<(String, String)> {
+    let started = std::time::Instant::now();
+    let res = get_body_once(url_s).await;
+    metrics().observe_since("sjmb_url_fetch_seconds", &[], started);
+    if res.is_err() {
+        metrics().inc("sjmb_url_fetch_errors_total", &[]);
+    }
+    res
+}
+
+async fn get_body_once(url_s: &str) -> anyhow::Result<(String, String)> {
     // We want a normalized and valid url, IDN handled etc.
     let url = Url::parse(url_s)?;
 
@@ -87,7 +141,7 @@ pub async fn get_body(url_s: &str) -> anyhow::Result<(String, String)> {
             .ok_or(anyhow!("No content-type in response"))?
             .as_bytes(),
     )
-        .to_string();
+    .to_string();
 
     let body = resp.text().await?;
     Ok((body, ct))